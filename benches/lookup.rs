@@ -0,0 +1,55 @@
+//! Chord lookup latency on the shipped dictionary.
+//!
+//! Run with `cargo bench --bench lookup`. `string key` reproduces the old
+//! `format!("{:?}")` + sort + join lookup as a baseline.
+
+use evdev::KeyCode;
+use std::{
+    collections::HashMap,
+    hint::black_box,
+    path::Path,
+    time::Instant,
+};
+use zipchord::ChordLibrary;
+
+const ITERATIONS: u32 = 1_000_000;
+
+fn main() {
+    let (library, _) = ChordLibrary::load(Path::new("dictionaries/english.zc"))
+        .expect("Failed to load dictionaries/english.zc");
+
+    let hit = [KeyCode::KEY_H, KeyCode::KEY_T, KeyCode::KEY_E];
+    let miss = [KeyCode::KEY_Q, KeyCode::KEY_X, KeyCode::KEY_Z];
+    let single = [KeyCode::KEY_A];
+
+    let strings: HashMap<String, String> = [(string_key(&hit), "there".to_string())].into();
+
+    for (name, chord) in [("hit", &hit[..]), ("miss", &miss[..]), ("single key", &single[..])] {
+        report(&format!("resolve ({})", name), || {
+            black_box(library.resolve(black_box(chord)));
+        });
+        report(&format!("string key ({})", name), || {
+            black_box(strings.get(&string_key(black_box(chord))).cloned());
+        });
+    }
+}
+
+fn string_key(chord: &[KeyCode]) -> String {
+    let mut keys: Vec<String> = chord.iter().map(|k| format!("{:?}", k)).collect();
+    keys.sort();
+    keys.join("+")
+}
+
+fn report(name: &str, mut lookup: impl FnMut()) {
+    // Warm up caches before timing
+    for _ in 0..ITERATIONS / 10 {
+        lookup();
+    }
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        lookup();
+    }
+    let per_lookup = start.elapsed().as_nanos() as f64 / f64::from(ITERATIONS);
+    println!("{:<24} {:>8.1} ns/lookup", name, per_lookup);
+}
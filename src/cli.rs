@@ -0,0 +1,211 @@
+use anyhow::{bail, Context, Result};
+use std::{path::PathBuf, time::Duration};
+use zipchord::convert::{Format, StenoMapping};
+use zipchord::library::Layout;
+use zipchord::stream::ChordConfig;
+
+pub const USAGE: &str = "\
+Usage: zipchord [COMMAND]
+
+Commands:
+  run [OPTIONS]       Detect chords and type their expansions (default)
+  compile [FILE...]   Build the .zcc cache for FILEs, or every library in library_path
+  lookup WORD...      List the chords and shorthands typing each WORD in the loaded
+                      libraries
+  import [OPTIONS] FILE [OUTPUT]
+                      Convert another tool's dictionary to a .zc library, written to
+                      OUTPUT or stdout
+  export [OPTIONS] FILE [OUTPUT]
+                      Convert a .zc library to another tool's dictionary, written to
+                      OUTPUT or stdout
+  record [--redact] FILE
+                      Write every key press and release to the trace FILE until
+                      interrupted; --redact hides the keys typing characters
+  replay [OPTIONS] FILE
+                      Detect chords in the trace FILE and print them with their
+                      expansions
+  calibrate           Time words typed normally and chords pressed on prompt, and
+                      save the detection values telling them apart best
+  help                Show this message
+
+Conversion options:
+  --format FORMAT     ahk-chords, ahk-shorthands, plover, espanso or csv (default:
+                      from the extension of the dictionary file)
+  --layout LAYOUT     Keyboard layout mapping characters to keys: qwerty, colemak,
+                      dvorak or an XKB keymap file (default: the configured layout)
+  --steno-map FILE    Plover keymap JSON mapping steno keys to keyboard keys
+                      (default: Plover's QWERTY keymap)
+
+Detection options, overriding the configured values:
+  --chord-window MS   Base time window in which keys count as pressed together
+  --roll-threshold N  Roll score from 0 to 1 above which keys count as rolled
+  --typing-speed-factor N
+                      How much the typing speed adjusts the window, 0 to 1
+  --min-overlap-ratio N
+                      Share of the window keys must overlap to count as a chord";
+
+#[derive(Debug)]
+pub enum Command {
+    Run(Tuning),
+    Record { output: PathBuf, redact: bool },
+    Replay { trace: PathBuf, tuning: Tuning },
+    Calibrate,
+    Compile { files: Vec<PathBuf> },
+    Lookup { words: Vec<String> },
+    Import(Conversion),
+    Export(Conversion),
+    Help,
+}
+
+/// Arguments of `import` and `export`
+#[derive(Debug)]
+pub struct Conversion {
+    pub format: Option<Format>,
+    /// Overrides the configured layout
+    pub layout: Option<Layout>,
+    pub steno: StenoMapping,
+    pub input: PathBuf,
+    pub output: Option<PathBuf>,
+}
+
+/// Chord detection values given on the command line
+#[derive(Debug, Default)]
+pub struct Tuning {
+    pub chord_window: Option<Duration>,
+    pub roll_threshold: Option<f32>,
+    pub typing_speed_factor: Option<f32>,
+    pub min_overlap_ratio: Option<f32>,
+}
+
+impl Tuning {
+    pub fn apply(&self, detection: &mut ChordConfig) {
+        if let Some(window) = self.chord_window {
+            detection.base_chord_window = window;
+        }
+        if let Some(threshold) = self.roll_threshold {
+            detection.roll_threshold = threshold;
+        }
+        if let Some(factor) = self.typing_speed_factor {
+            detection.typing_speed_factor = factor;
+        }
+        if let Some(ratio) = self.min_overlap_ratio {
+            detection.min_overlap_ratio = ratio;
+        }
+    }
+}
+
+impl Command {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let Some(command) = args.next() else {
+            return Ok(Command::Run(Tuning::default()));
+        };
+
+        match command.as_str() {
+            "run" => {
+                let (tuning, files) = Self::parse_tuning(args)?;
+                if !files.is_empty() {
+                    bail!("`run` takes no files\n\n{}", USAGE);
+                }
+                Ok(Command::Run(tuning))
+            }
+            "record" => {
+                let mut redact = false;
+                let mut files = Vec::new();
+                for arg in args {
+                    match arg.as_str() {
+                        "--redact" => redact = true,
+                        _ if arg.starts_with("--") => bail!("Unknown option `{}`\n\n{}", arg, USAGE),
+                        _ => files.push(PathBuf::from(arg)),
+                    }
+                }
+                let mut files = files.into_iter();
+                let (Some(output), None) = (files.next(), files.next()) else {
+                    bail!("`record` takes one trace file\n\n{}", USAGE);
+                };
+                Ok(Command::Record { output, redact })
+            }
+            "replay" => {
+                let (tuning, files) = Self::parse_tuning(args)?;
+                let mut files = files.into_iter();
+                let (Some(trace), None) = (files.next(), files.next()) else {
+                    bail!("`replay` takes one trace file\n\n{}", USAGE);
+                };
+                Ok(Command::Replay { trace, tuning })
+            }
+            "calibrate" => {
+                if let Some(arg) = args.next() {
+                    bail!("`calibrate` takes no arguments, got `{}`\n\n{}", arg, USAGE);
+                }
+                Ok(Command::Calibrate)
+            }
+            "compile" => Ok(Command::Compile {
+                files: args.map(PathBuf::from).collect(),
+            }),
+            "lookup" => {
+                let words: Vec<String> = args.collect();
+                if words.is_empty() {
+                    bail!("`lookup` takes at least one word\n\n{}", USAGE);
+                }
+                Ok(Command::Lookup { words })
+            }
+            "import" => Ok(Command::Import(Self::parse_conversion(&command, args)?)),
+            "export" => Ok(Command::Export(Self::parse_conversion(&command, args)?)),
+            "help" | "-h" | "--help" => Ok(Command::Help),
+            other => bail!("Unknown command `{}`\n\n{}", other, USAGE),
+        }
+    }
+
+    fn parse_conversion(command: &str, mut args: impl Iterator<Item = String>) -> Result<Conversion> {
+        let mut format = None;
+        let mut layout = None;
+        let mut steno = StenoMapping::default();
+        let mut files = Vec::new();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--format" => format = Some(Self::value(&mut args, &arg)?.parse()?),
+                "--layout" => layout = Some(Self::value(&mut args, &arg)?.parse()?),
+                "--steno-map" => steno = StenoMapping::load(Self::value(&mut args, &arg)?.as_ref())?,
+                _ if arg.starts_with("--") => bail!("Unknown option `{}`\n\n{}", arg, USAGE),
+                _ => files.push(PathBuf::from(arg)),
+            }
+        }
+
+        let mut files = files.into_iter();
+        let (Some(input), output, None) = (files.next(), files.next(), files.next()) else {
+            bail!("`{}` takes an input file and an optional output file\n\n{}", command, USAGE);
+        };
+        Ok(Conversion { format, layout, steno, input, output })
+    }
+
+    // Detection options and the remaining file arguments
+    fn parse_tuning(mut args: impl Iterator<Item = String>) -> Result<(Tuning, Vec<PathBuf>)> {
+        let mut tuning = Tuning::default();
+        let mut files = Vec::new();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--chord-window" => {
+                    let millis: u64 = Self::value(&mut args, &arg)?.parse()
+                        .with_context(|| format!("Invalid `{}`", arg))?;
+                    tuning.chord_window = Some(Duration::from_millis(millis));
+                }
+                "--roll-threshold" => tuning.roll_threshold = Some(Self::ratio(&mut args, &arg)?),
+                "--typing-speed-factor" => tuning.typing_speed_factor = Some(Self::ratio(&mut args, &arg)?),
+                "--min-overlap-ratio" => tuning.min_overlap_ratio = Some(Self::ratio(&mut args, &arg)?),
+                _ if arg.starts_with("--") => bail!("Unknown option `{}`\n\n{}", arg, USAGE),
+                _ => files.push(PathBuf::from(arg)),
+            }
+        }
+        Ok((tuning, files))
+    }
+
+    fn ratio(args: &mut impl Iterator<Item = String>, option: &str) -> Result<f32> {
+        Self::value(args, option)?.parse()
+            .with_context(|| format!("Invalid `{}`", option))
+    }
+
+    fn value(args: &mut impl Iterator<Item = String>, option: &str) -> Result<String> {
+        args.next().with_context(|| format!("Missing value after `{}`", option))
+    }
+}
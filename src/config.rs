@@ -4,14 +4,33 @@ use dirs::home_dir;
 use std::{
     env, path::{Path, PathBuf}, time::Duration
 };
+use zipchord::library::{DuplicatePolicy, Layout};
+use zipchord::stream::ChordConfig;
 
 const DEFAULT_TIMEOUT_MS: u64 = 20;
+const DEFAULT_SEQUENCE_TIMEOUT_MS: u64 = 1000;
+const DEFAULT_ROLL_THRESHOLD: f32 = 0.7;
 const APP_NAME: &str = "chords";
+pub const LIBRARY_EXTENSION: &str = "zc";
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AppConfig {
     pub library_path: PathBuf,
+    /// Library file names loaded first, highest priority first
+    pub library_order: Vec<String>,
+    /// Library file names that are never loaded
+    pub library_disabled: Vec<String>,
     pub chord_timeout: Duration,
+    pub sequence_timeout: Duration,
+    pub duplicate_policy: DuplicatePolicy,
+    /// Use and refresh the compiled `.zcc` cache next to each library
+    pub library_cache: bool,
+    /// Keyboard layout for chords written by character
+    pub layout: Layout,
+    /// Timing values telling chords from rolled keys
+    pub detection: ChordConfig,
+    /// Hide the keys typing characters in recorded traces
+    pub record_redact: bool,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -54,12 +73,61 @@ impl AppConfig {
                         config.library_path = Self::expand_path(value)
                             .context("Failed to expand library path")?;
                     }
+                    "library_order" => {
+                        config.library_order = Self::parse_list(value);
+                    }
+                    "library_disabled" => {
+                        config.library_disabled = Self::parse_list(value);
+                    }
+                    "library_cache" => {
+                        config.library_cache = Self::parse_bool(value)
+                            .context("Failed to parse library cache")?;
+                    }
                     "chord_timeout" => {
                         config.chord_timeout = Duration::from_millis(
                             value.parse()
                                 .context("Failed to parse chord timeout")?
                         );
                     }
+                    "sequence_timeout" => {
+                        config.sequence_timeout = Duration::from_millis(
+                            value.parse()
+                                .context("Failed to parse sequence timeout")?
+                        );
+                    }
+                    "layout" => {
+                        config.layout = match Layout::builtin(value) {
+                            Some(layout) => layout,
+                            None => Layout::from_xkb(&Self::expand_path(value)?)
+                                .context("Failed to load keyboard layout")?,
+                        };
+                    }
+                    "chord_window" => {
+                        config.detection.base_chord_window = Duration::from_millis(
+                            value.parse()
+                                .context("Failed to parse chord window")?
+                        );
+                    }
+                    "roll_threshold" => {
+                        config.detection.roll_threshold = value.parse()
+                            .context("Failed to parse roll threshold")?;
+                    }
+                    "typing_speed_factor" => {
+                        config.detection.typing_speed_factor = value.parse()
+                            .context("Failed to parse typing speed factor")?;
+                    }
+                    "min_overlap_ratio" => {
+                        config.detection.min_overlap_ratio = value.parse()
+                            .context("Failed to parse minimum overlap ratio")?;
+                    }
+                    "record_redact" => {
+                        config.record_redact = Self::parse_bool(value)
+                            .context("Failed to parse record redact")?;
+                    }
+                    "duplicate_policy" => {
+                        config.duplicate_policy = value.parse()
+                            .context("Failed to parse duplicate policy")?;
+                    }
                     _ => continue
                 }
             }
@@ -68,6 +136,89 @@ impl AppConfig {
         Ok(())
     }
 
+    /// Set `key = value` lines in the config file, replacing the lines of
+    /// those keys already there and keeping their comments
+    pub fn save_settings(settings: &[(&str, String)]) -> Result<PathBuf> {
+        let config_path = Self::config_file_path().ok_or(ConfigError::MissingDirectory)?;
+        let content = match std::fs::read_to_string(&config_path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", config_path.display())),
+        };
+
+        let mut saved = Vec::new();
+        let mut lines = Vec::new();
+        for line in content.lines() {
+            let (setting, comment) = line.split_once(';').map_or((line, None), |(setting, comment)| (setting, Some(comment)));
+            let key = setting.split_once('=').map(|(key, _)| key.trim().to_lowercase());
+            match settings.iter().find(|(name, _)| key.as_deref() == Some(*name)) {
+                Some((name, value)) => {
+                    saved.push(*name);
+                    match comment {
+                        Some(comment) => lines.push(format!("{} = {} ;{}", name, value, comment)),
+                        None => lines.push(format!("{} = {}", name, value)),
+                    }
+                }
+                None => lines.push(line.to_string()),
+            }
+        }
+        for (name, value) in settings {
+            if !saved.contains(name) {
+                lines.push(format!("{} = {}", name, value));
+            }
+        }
+
+        if let Some(dir) = config_path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        std::fs::write(&config_path, lines.join("\n") + "\n")
+            .with_context(|| format!("Failed to write {}", config_path.display()))?;
+        Ok(config_path)
+    }
+
+    /// Every enabled `.zc` file in `library_path`, highest priority first.
+    ///
+    /// Files named in `library_order` come first in that order, the rest
+    /// follow alphabetically.
+    pub fn library_files(&self) -> Result<Vec<PathBuf>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(&self.library_path)
+            .with_context(|| format!("Failed to read {}", self.library_path.display()))?
+        {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == LIBRARY_EXTENSION) && path.is_file() {
+                if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+
+        names.retain(|name| !self.library_disabled.contains(name));
+        names.sort_by_key(|name| {
+            let rank = self.library_order.iter().position(|n| n == name);
+            (rank.unwrap_or(usize::MAX), name.clone())
+        });
+
+        Ok(names.into_iter().map(|name| self.library_path.join(name)).collect())
+    }
+
+    fn parse_list(value: &str) -> Vec<String> {
+        value.split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    fn parse_bool(value: &str) -> Result<bool> {
+        match value.to_lowercase().as_str() {
+            "true" | "yes" | "on" | "1" => Ok(true),
+            "false" | "no" | "off" | "0" => Ok(false),
+            _ => Err(ConfigError::Validation(format!("Expected true or false, got `{}`", value)).into()),
+        }
+    }
+
     fn config_file_path() -> Option<PathBuf> {
         home_dir().map(|path| path.join(APP_NAME).join("config.ini"))
     }
@@ -75,7 +226,18 @@ impl AppConfig {
     fn defaults() -> Result<Self> {
         Ok(Self {
             library_path: Self::default_library_path()?,
+            library_order: Vec::new(),
+            library_disabled: Vec::new(),
             chord_timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
+            sequence_timeout: Duration::from_millis(DEFAULT_SEQUENCE_TIMEOUT_MS),
+            duplicate_policy: DuplicatePolicy::default(),
+            library_cache: true,
+            layout: Layout::default(),
+            detection: ChordConfig {
+                roll_threshold: DEFAULT_ROLL_THRESHOLD,
+                ..ChordConfig::default()
+            },
+            record_redact: false,
         })
     }
 
@@ -107,7 +269,37 @@ impl AppConfig {
                 format!("Chord timeout cannot exceed 1000ms (got {}ms)", self.chord_timeout.as_millis())
             ).into());
         }
-        
+
+        if self.sequence_timeout.is_zero() {
+            return Err(ConfigError::Validation(
+                "Sequence timeout must be greater than 0ms".to_string()
+            ).into());
+        }
+
+        self.validate_detection()
+    }
+
+    /// Check the chord detection values, which the command line can override
+    pub fn validate_detection(&self) -> Result<()> {
+        let detection = &self.detection;
+        if detection.base_chord_window.is_zero() || detection.base_chord_window > Duration::from_secs(1) {
+            return Err(ConfigError::Validation(format!(
+                "Chord window must be between 1 and 1000ms (got {}ms)", detection.base_chord_window.as_millis()
+            )).into());
+        }
+
+        for (name, value) in [
+            ("Roll threshold", detection.roll_threshold),
+            ("Typing speed factor", detection.typing_speed_factor),
+            ("Minimum overlap ratio", detection.min_overlap_ratio),
+        ] {
+            if !(0.0..=1.0).contains(&value) {
+                return Err(ConfigError::Validation(
+                    format!("{} must be between 0 and 1 (got {})", name, value)
+                ).into());
+            }
+        }
+
         Ok(())
     }
 }
@@ -0,0 +1,153 @@
+//! Dictionaries of the original AutoHotkey ZipChord for Windows.
+//!
+//! Both chord and shorthand dictionaries are text files with one
+//! `input<TAB>expansion` entry per line. A chord's input is the set of
+//! characters pressed together; a shorthand's is the abbreviation typed.
+//! Expansions use `~` to glue affixes, as `.zc` files do.
+
+use std::path::Path;
+
+use super::{chord_of, Importer};
+use crate::library::{ChordLibrary, Diagnostics, Expansion, Layout, Severity};
+
+/// Import a chord dictionary
+pub fn import_chords(path: &Path, content: &str, layout: &Layout) -> (ChordLibrary, Diagnostics) {
+    import(path, content, |importer, number, chord, expansion| {
+        match chord_of(chord, layout) {
+            Ok(chord) => importer.insert(vec![chord], expansion, number),
+            Err(message) => importer.report(Severity::Error, number, 1, message),
+        }
+    })
+}
+
+/// Import a shorthand dictionary into `[shorthands]`, which expand when a
+/// word delimiter is typed after them, as they do in ZipChord
+pub fn import_shorthands(path: &Path, content: &str) -> (ChordLibrary, Diagnostics) {
+    import(path, content, |importer, number, abbreviation, expansion| {
+        importer.insert_shorthand(abbreviation, expansion, number);
+    })
+}
+
+fn import(
+    path: &Path,
+    content: &str,
+    mut insert: impl FnMut(&mut Importer, usize, &str, Expansion),
+) -> (ChordLibrary, Diagnostics) {
+    let mut importer = Importer::new(path);
+
+    for (number, line) in content.lines().enumerate() {
+        let number = number + 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let Some((input, value)) = line.split_once('\t') else {
+            importer.report(Severity::Warning, number, 1,
+                "Expected `input<TAB>expansion`, ignoring the line".to_string());
+            continue;
+        };
+        // The input column is column 1, the expansion follows the tab
+        let value_column = input.len() + 2;
+
+        if input.is_empty() || value.is_empty() {
+            importer.report(Severity::Error, number, 1, "Entry without input or expansion".to_string());
+            continue;
+        }
+        // AutoHotkey key names such as `{Enter}` or `{Left 3}`
+        if let Some(start) = value.find('{') {
+            let end = value[start..].find('}').map_or(value.len(), |end| start + end + 1);
+            importer.report(Severity::Error, number, value_column + start, format!(
+                "AutoHotkey key `{}` can't be typed by zipchord", &value[start..end]));
+            continue;
+        }
+
+        insert(&mut importer, number, input, Expansion::parse(value));
+    }
+
+    importer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::{ChordKey, Section};
+    use evdev::KeyCode;
+
+    fn reports(diagnostics: &Diagnostics) -> Vec<(usize, usize, Severity, &str)> {
+        diagnostics.iter()
+            .map(|d| (d.line, d.column, d.severity, d.message.as_str()))
+            .collect()
+    }
+
+    fn chord(keys: &[KeyCode]) -> Vec<ChordKey> {
+        vec![ChordKey::new(keys).unwrap()]
+    }
+
+    #[test]
+    fn chords_go_to_the_section_of_their_glue() {
+        let content = "th\tthe\r\npr\tpre~\n\nig\t~ing\nco\t~.com~\n";
+        let (library, diagnostics) = import_chords(Path::new("chords.txt"), content, &Layout::default());
+        assert_eq!(diagnostics.len(), 0, "{:?}", reports(&diagnostics));
+
+        let (section, the) = library.get(&chord(&[KeyCode::KEY_T, KeyCode::KEY_H])).unwrap();
+        assert_eq!((section, the.text.as_str()), (Section::Chord, "the"));
+        assert_eq!(the.source.as_ref().unwrap().line, 1);
+        assert_eq!(library.get(&chord(&[KeyCode::KEY_P, KeyCode::KEY_R])).unwrap().0, Section::Prefix);
+        let (section, ing) = library.get(&chord(&[KeyCode::KEY_I, KeyCode::KEY_G])).unwrap();
+        assert_eq!((section, ing.source.as_ref().unwrap().line), (Section::Suffix, 4));
+        assert_eq!(library.get(&chord(&[KeyCode::KEY_C, KeyCode::KEY_O])).unwrap().0, Section::Infix);
+
+        // Characters follow the layout
+        let colemak = Layout::builtin("colemak").unwrap();
+        let (library, _) = import_chords(Path::new("chords.txt"), "th\tthe\n", &colemak);
+        assert!(library.get(&chord(&[KeyCode::KEY_F, KeyCode::KEY_H])).is_some());
+    }
+
+    #[test]
+    fn unsupported_entries_are_reported() {
+        let content = [
+            "th\tthe",
+            "ke\t{Enter}",
+            "ab\tabout {Left 3}",
+            // Hotstrings with options belong to AutoHotkey scripts, not dictionaries
+            ":*:btw::by the way",
+            "TH\tThe",
+            "ht\tother",
+            "\tempty",
+            "sp\tspaced ",
+            "cf\t leading",
+            "é\tcafé",
+            "abcdefghi\ttoo long",
+        ].join("\n");
+        let (library, diagnostics) = import_chords(Path::new("chords.txt"), &content, &Layout::default());
+
+        assert_eq!(reports(&diagnostics), [
+            (2, 4, Severity::Error, "AutoHotkey key `{Enter}` can't be typed by zipchord"),
+            (3, 10, Severity::Error, "AutoHotkey key `{Left 3}` can't be typed by zipchord"),
+            (4, 1, Severity::Warning, "Expected `input<TAB>expansion`, ignoring the line"),
+            (5, 1, Severity::Error, "`T` needs Shift on the qwerty layout, which a chord can't hold"),
+            (6, 1, Severity::Warning, "Already defined on line 1, keeping that definition"),
+            (7, 1, Severity::Error, "Entry without input or expansion"),
+            (8, 1, Severity::Error, "\"spaced \" can't be written to a .zc library without glue after it"),
+            (9, 1, Severity::Error, "\" leading\" can't be written to a .zc library without glue before it"),
+            (10, 1, Severity::Error, "No key types `é` on the qwerty layout"),
+            (11, 1, Severity::Error, "Chord has more than 8 keys"),
+        ]);
+        assert_eq!(library.get(&chord(&[KeyCode::KEY_T, KeyCode::KEY_H])).unwrap().1.text, "the");
+        assert_eq!(library.to_document().unwrap().to_string().matches("=>").count(), 1);
+    }
+
+    #[test]
+    fn shorthands_keep_the_first_definition() {
+        let content = "btw\tby the way\nomw\ton my way~\nbtw\tbetween\nidk\t{Space}\n";
+        let (library, diagnostics) = import_shorthands(Path::new("shorthands.txt"), content);
+
+        assert_eq!(reports(&diagnostics), [
+            (3, 1, Severity::Warning, "Already defined on line 1, keeping that definition"),
+            (4, 5, Severity::Error, "AutoHotkey key `{Space}` can't be typed by zipchord"),
+        ]);
+        assert_eq!(library.shorthands()["btw"].text, "by the way");
+        assert!(library.shorthands()["omw"].attach_right);
+        assert_eq!(library.shorthands().len(), 2);
+    }
+}
@@ -0,0 +1,236 @@
+//! CSV chord maps in the style of CharaChorder and similar chording devices.
+//!
+//! Each row holds the characters pressed together and the text they type:
+//! `input,output`, with an optional `input,output` header row. Input
+//! characters map to keys through a [`Layout`], so the same file can drive
+//! a device and zipchord on a regular keyboard. Outputs keep the `~` glue
+//! markers of `.zc` files. Fields follow RFC 4180 quoting.
+
+use std::path::Path;
+
+use super::{chord_of, entries, Exporter, Importer};
+use crate::library::{ChordLibrary, Diagnostics, Expansion, Keystroke, Layout, Severity};
+
+const HEADER: [&str; 2] = ["input", "output"];
+
+/// Import a chord map
+pub fn import(path: &Path, content: &str, layout: &Layout) -> (ChordLibrary, Diagnostics) {
+    let mut importer = Importer::new(path);
+
+    let records = match records(content) {
+        Ok(records) => records,
+        Err(line) => {
+            importer.report(Severity::Error, line, 1, "Quoted field is never closed".to_string());
+            return importer.finish();
+        }
+    };
+
+    for (index, (number, fields)) in records.into_iter().enumerate() {
+        if index == 0 && fields.iter().map(|field| field.to_lowercase()).eq(HEADER) {
+            continue;
+        }
+        let [input, output, ..] = fields.as_slice() else {
+            importer.report(Severity::Error, number, 1, "Expected `input,output`".to_string());
+            continue;
+        };
+        if input.is_empty() || output.is_empty() {
+            importer.report(Severity::Error, number, 1, "Row without input or output".to_string());
+            continue;
+        }
+
+        match chord_of(input, layout) {
+            Ok(chord) => importer.insert(vec![chord], Expansion::parse(output), number),
+            Err(message) => importer.report(Severity::Error, number, 1, message),
+        }
+    }
+
+    importer.finish()
+}
+
+/// Export a library as a chord map
+pub fn export(library: &ChordLibrary, layout: &Layout) -> (String, Diagnostics) {
+    let mut exporter = Exporter::default();
+    let mut out = format!("{}\r\n", HEADER.join(","));
+    exporter.skip_shorthands(library, "a chord map");
+
+    for (strokes, expansion) in entries(library) {
+        let [chord] = strokes.as_slice() else {
+            exporter.report(expansion, Severity::Error,
+                "Sequences of several strokes can't be written as a chord map row".to_string());
+            continue;
+        };
+
+        let input = chord.keys()
+            .map(|key| layout.character(Keystroke { key, shift: false })
+                .ok_or_else(|| format!("`{:?}` types no character on the {} layout", key, layout.name())))
+            .collect::<Result<String, _>>();
+        match input {
+            Ok(input) => {
+                out.push_str(&format!("{},{}\r\n", field(&input), field(&expansion.to_value())));
+            }
+            Err(message) => exporter.report(expansion, Severity::Error, message),
+        }
+    }
+
+    (out, exporter.finish())
+}
+
+// Split CSV into records with the 1-based line each starts on. Fails with
+// the line of a quoted field that is never closed.
+fn records(content: &str) -> Result<Vec<(usize, Vec<String>)>, usize> {
+    let mut records = Vec::new();
+    let mut chars = content.chars().peekable();
+    let mut line = 1;
+
+    while chars.peek().is_some() {
+        let start = line;
+        let mut fields = Vec::new();
+        let mut field = String::new();
+
+        loop {
+            match chars.next() {
+                Some('"') if field.is_empty() => {
+                    // Quoted field, `""` is a literal quote
+                    loop {
+                        match chars.next() {
+                            Some('"') if chars.peek() == Some(&'"') => {
+                                chars.next();
+                                field.push('"');
+                            }
+                            Some('"') => break,
+                            Some(c) => {
+                                if c == '\n' {
+                                    line += 1;
+                                }
+                                field.push(c);
+                            }
+                            None => return Err(start),
+                        }
+                    }
+                }
+                Some(',') => fields.push(std::mem::take(&mut field)),
+                Some('\r') if chars.peek() == Some(&'\n') => {}
+                Some('\n') | None => {
+                    line += 1;
+                    fields.push(field);
+                    break;
+                }
+                Some(c) => field.push(c),
+            }
+        }
+
+        // Skip blank lines
+        if fields.len() > 1 || !fields[0].is_empty() {
+            records.push((start, fields));
+        }
+    }
+
+    Ok(records)
+}
+
+// Quote a field when it would otherwise be read back differently
+fn field(value: &str) -> String {
+    let plain = !value.contains([',', '"', '\r', '\n']) && value.trim() == value;
+    if plain {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::{ChordKey, Section};
+    use evdev::KeyCode;
+
+    fn reports(diagnostics: &Diagnostics) -> Vec<(usize, Severity, &str)> {
+        diagnostics.iter()
+            .map(|d| (d.line, d.severity, d.message.as_str()))
+            .collect()
+    }
+
+    fn chord(keys: &[KeyCode]) -> Vec<ChordKey> {
+        vec![ChordKey::new(keys).unwrap()]
+    }
+
+    #[test]
+    fn quoted_fields_are_read() {
+        let content = [
+            "Input,Output",
+            "th,the",
+            "\"pr\",pre~",
+            "co,\"~,~\"",
+            "qu,\"say \"\"hi\"\"\",ignored",
+            "ml,\"two\nlines\"",
+            "sp,\" spaced\"",
+            "",
+            "onlyone",
+            ",empty",
+            "TH,The",
+            "ht,dup",
+        ].join("\r\n");
+        let (library, diagnostics) = import(Path::new("chords.csv"), &content, &Layout::default());
+
+        assert_eq!(reports(&diagnostics), [
+            (6, Severity::Error, "\"two\\nlines\" can't be written to a .zc library, it contains '\\n'"),
+            (8, Severity::Error, "\" spaced\" can't be written to a .zc library without glue before it"),
+            (10, Severity::Error, "Expected `input,output`"),
+            (11, Severity::Error, "Row without input or output"),
+            (12, Severity::Error, "`T` needs Shift on the qwerty layout, which a chord can't hold"),
+            (13, Severity::Warning, "Already defined on line 2, keeping that definition"),
+        ]);
+        let get = |keys: &[KeyCode]| {
+            let (section, expansion) = library.get(&chord(keys)).unwrap();
+            (section, expansion.text.as_str())
+        };
+        assert_eq!(get(&[KeyCode::KEY_T, KeyCode::KEY_H]), (Section::Chord, "the"));
+        assert_eq!(get(&[KeyCode::KEY_P, KeyCode::KEY_R]), (Section::Prefix, "pre"));
+        assert_eq!(get(&[KeyCode::KEY_C, KeyCode::KEY_O]), (Section::Infix, ","));
+        assert_eq!(get(&[KeyCode::KEY_Q, KeyCode::KEY_U]), (Section::Chord, "say \"hi\""));
+
+        let (library, diagnostics) = import(Path::new("chords.csv"), "th,the\nab,\"open\nx,y\n", &Layout::default());
+        assert_eq!(reports(&diagnostics), [(2, Severity::Error, "Quoted field is never closed")]);
+        assert_eq!(library.section(Section::Chord).len(), 0);
+    }
+
+    #[test]
+    fn export_quotes_fields_and_reports_what_it_cannot_write() {
+        let library = ChordLibrary::builder()
+            .chord("t+h", "the").unwrap()
+            .chord("q+u", "say \"hi\"").unwrap()
+            .entry(Section::Infix, "c+o", "~,~").unwrap()
+            .entry(Section::Suffix, "i+g", "~ing").unwrap()
+            .chord("KEY_F13+a", "unmapped").unwrap()
+            .entry(Section::Exception, "t+h a+b", "sequence").unwrap()
+            .shorthand("btw", "by the way").unwrap()
+            .build();
+        let (exported, diagnostics) = export(&library, &Layout::default());
+
+        // Keys are written in key code order
+        let mut rows: Vec<&str> = exported.split_terminator("\r\n").collect();
+        assert_eq!(rows.remove(0), "input,output");
+        rows.sort_unstable();
+        assert_eq!(rows, ["ig,~ing", "oc,\"~,~\"", "qu,\"say \"\"hi\"\"\"", "th,the"]);
+
+        let mut messages: Vec<_> = reports(&diagnostics).into_iter().map(|(_, _, message)| message).collect();
+        messages.sort_unstable();
+        assert_eq!(messages, [
+            "Sequences of several strokes can't be written as a chord map row",
+            "Shorthand `btw` can't be written to a chord map",
+            "`KEY_F13` types no character on the qwerty layout",
+        ]);
+
+        // What is written reads back as the same entries
+        let (imported, diagnostics) = import(Path::new("chords.csv"), &exported, &Layout::default());
+        assert_eq!(diagnostics.len(), 0);
+        let mut count = 0;
+        for section in Section::ALL {
+            for (chord, expansion) in imported.section(section) {
+                assert_eq!(library.section(section)[chord].to_value(), expansion.to_value());
+                count += 1;
+            }
+        }
+        assert_eq!(count, rows.len());
+    }
+}
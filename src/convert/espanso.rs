@@ -0,0 +1,189 @@
+//! espanso match files.
+//!
+//! Each entry of `matches:` with a `trigger` (or several `triggers`) and a
+//! plain `replace` text becomes a `[shorthands]` entry, which expands when
+//! a word delimiter follows the trigger, like an espanso `word: true`
+//! match. Variables, forms, regexes, images and other features zipchord
+//! has no equivalent for are reported.
+
+use std::path::Path;
+use yaml_rust2::{Yaml, YamlLoader};
+
+use super::Importer;
+use crate::library::{ChordLibrary, Diagnostics, Expansion, Severity};
+
+/// Match keys whose feature can't be converted
+const UNSUPPORTED: [(&str, &str); 6] = [
+    ("regex", "regex triggers"),
+    ("form", "forms"),
+    ("image_path", "images"),
+    ("html", "HTML replacements"),
+    ("markdown", "Markdown replacements"),
+    ("vars", "variables"),
+];
+
+/// Import a match file
+pub fn import(path: &Path, content: &str) -> (ChordLibrary, Diagnostics) {
+    let mut importer = Importer::new(path);
+
+    let documents = match YamlLoader::load_from_str(content) {
+        Ok(documents) => documents,
+        Err(e) => {
+            importer.report(Severity::Error, e.marker().line(), e.marker().col() + 1,
+                format!("Invalid espanso match file: {}", e));
+            return importer.finish();
+        }
+    };
+    let Some(file) = documents.first() else {
+        return importer.finish();
+    };
+
+    if !file["imports"].is_badvalue() {
+        let number = line_of(content, 0, "imports:");
+        importer.report(Severity::Warning, number, 1,
+            "Imported match files are not followed, import them separately".to_string());
+    }
+
+    let matches = file["matches"].as_vec().map_or(&[][..], Vec::as_slice);
+    // Entries carry no position, so each one is located after the previous one
+    let mut offset = 0;
+    for entry in matches {
+        let triggers = match (entry["trigger"].as_str(), entry["triggers"].as_vec()) {
+            (Some(trigger), _) => vec![trigger],
+            (None, Some(triggers)) => triggers.iter().filter_map(Yaml::as_str).collect(),
+            (None, None) => Vec::new(),
+        };
+        let needle = triggers.first().copied().or(entry["regex"].as_str());
+        if let Some(at) = needle.and_then(|needle| content[offset..].find(needle)) {
+            offset += at;
+        }
+        let number = line_of(content, offset, "");
+
+        if let Some((_, feature)) = UNSUPPORTED.iter().find(|(key, _)| !entry[*key].is_badvalue()) {
+            importer.report(Severity::Error, number, 1,
+                format!("Match {} uses {}, which zipchord can't expand", describe(&triggers), feature));
+            continue;
+        }
+        if triggers.is_empty() {
+            importer.report(Severity::Error, number, 1, "Match without a trigger".to_string());
+            continue;
+        }
+        let Some(replace) = entry["replace"].as_str() else {
+            importer.report(Severity::Error, number, 1,
+                format!("Match {} has no plain `replace` text", describe(&triggers)));
+            continue;
+        };
+        if let Some(start) = replace.find("{{") {
+            let end = replace[start..].find("}}").map_or(replace.len(), |end| start + end + 2);
+            importer.report(Severity::Error, number, 1, format!(
+                "Match {} uses the variable `{}`, which zipchord can't expand",
+                describe(&triggers), &replace[start..end]));
+            continue;
+        }
+        if replace.contains("$|$") {
+            importer.report(Severity::Error, number, 1, format!(
+                "Match {} moves the cursor with `$|$`, which zipchord can't do", describe(&triggers)));
+            continue;
+        }
+
+        for trigger in triggers {
+            let expansion = Expansion {
+                text: replace.to_string(),
+                attach_left: false,
+                attach_right: false,
+                source: None,
+            };
+            importer.insert_shorthand(trigger, expansion, number);
+        }
+    }
+
+    importer.finish()
+}
+
+// 1-based line of the first `needle` at or after byte `offset`
+fn line_of(content: &str, offset: usize, needle: &str) -> usize {
+    let at = content[offset..].find(needle).map_or(offset, |at| offset + at);
+    content[..at].matches('\n').count() + 1
+}
+
+fn describe(triggers: &[&str]) -> String {
+    match triggers.first() {
+        Some(trigger) => format!("`{}`", trigger),
+        None => "without a trigger".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reports(diagnostics: &Diagnostics) -> Vec<(usize, usize, Severity, &str)> {
+        diagnostics.iter()
+            .map(|d| (d.line, d.column, d.severity, d.message.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn matches_become_shorthands() {
+        let content = [
+            "imports:",
+            "  - other.yml",
+            "matches:",
+            "  - trigger: \":btw\"",
+            "    replace: \"by the way\"",
+            "    word: true",
+            "  - triggers: [\":omw\", \":otw\"]",
+            "    replace: \"on my way\"",
+            "  - trigger: \":date\"",
+            "    replace: \"{{mydate}}\"",
+            "  - regex: \"[0-9]+x\"",
+            "    replace: \"times\"",
+            "  - trigger: \":cur\"",
+            "    replace: \"a$|$b\"",
+            "  - trigger: \":form\"",
+            "    form: \"Hi [[name]]\"",
+            "  - trigger: \":sp\"",
+            "    replace: \"trailing \"",
+            "  - trigger: \":lead\"",
+            "    replace: \" leading\"",
+            "  - trigger: \":btw\"",
+            "    replace: \"again\"",
+            "  - trigger: \":glue\"",
+            "    replace: \"~ing\"",
+        ].join("\n");
+        let (library, diagnostics) = import(Path::new("base.yml"), &content);
+
+        assert_eq!(reports(&diagnostics), [
+            (1, 1, Severity::Warning, "Imported match files are not followed, import them separately"),
+            (9, 1, Severity::Error, "Match `:date` uses the variable `{{mydate}}`, which zipchord can't expand"),
+            (11, 1, Severity::Error, "Match without a trigger uses regex triggers, which zipchord can't expand"),
+            (13, 1, Severity::Error, "Match `:cur` moves the cursor with `$|$`, which zipchord can't do"),
+            (15, 1, Severity::Error, "Match `:form` uses forms, which zipchord can't expand"),
+            (17, 1, Severity::Error, "\"trailing \" can't be written to a .zc library without glue after it"),
+            (19, 1, Severity::Error, "\" leading\" can't be written to a .zc library without glue before it"),
+            (21, 1, Severity::Warning, "Already defined on line 4, keeping that definition"),
+            (23, 1, Severity::Error, "\"~ing\" can't be written to a .zc library without glue before it"),
+        ]);
+
+        let shorthands = library.shorthands();
+        assert_eq!(shorthands.len(), 3);
+        let btw = &shorthands[":btw"];
+        assert_eq!((btw.to_value().as_str(), btw.source.as_ref().unwrap().line), ("by the way", 4));
+        assert_eq!(shorthands[":omw"].text, "on my way");
+        assert_eq!(shorthands[":otw"].source.as_ref().unwrap().line, 7);
+    }
+
+    #[test]
+    fn yaml_errors_point_at_their_line() {
+        let content = "matches:\n  - trigger: \":a\"\n    replace: }\n  - trigger: \":b\"\n    replace: \"b\"\n";
+        let (library, diagnostics) = import(Path::new("base.yml"), content);
+        let [error] = reports(&diagnostics)[..] else { panic!("{:?}", reports(&diagnostics)) };
+        assert_eq!((error.0, error.1, error.2), (3, 14, Severity::Error));
+        assert!(error.3.starts_with("Invalid espanso match file"));
+        assert_eq!(library.shorthands().len(), 0);
+
+        // An empty file has no matches
+        let (library, diagnostics) = import(Path::new("base.yml"), "");
+        assert_eq!((library.shorthands().len(), diagnostics.len()), (0, 0));
+    }
+}
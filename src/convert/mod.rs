@@ -0,0 +1,258 @@
+//! Conversion between zipchord libraries and the dictionaries of other
+//! chording and text expansion tools.
+//!
+//! Importers never fail on a single bad entry: whatever can't be represented
+//! in a [`ChordLibrary`] is skipped and reported as a [`Diagnostic`].
+
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use crate::library::{
+    ChordKey, ChordLibrary, Diagnostic, Diagnostics, Expansion, Layout, Section, Severity, Source,
+    MAX_CHORD_KEYS,
+};
+
+pub mod ahk;
+pub mod csv;
+pub mod espanso;
+pub mod plover;
+
+pub use plover::StenoMapping;
+
+/// A dictionary format zipchord can convert
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Chord dictionary of the AutoHotkey ZipChord
+    AhkChords,
+    /// Shorthand dictionary of the AutoHotkey ZipChord
+    AhkShorthands,
+    /// Plover JSON steno dictionary
+    Plover,
+    /// espanso YAML match file
+    Espanso,
+    /// `input,output` chord map as used by chording devices
+    Csv,
+}
+
+impl Format {
+    /// Guess the format from a file extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "txt" => Some(Self::AhkChords),
+            "json" => Some(Self::Plover),
+            "yml" | "yaml" => Some(Self::Espanso),
+            "csv" => Some(Self::Csv),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "ahk" | "ahk-chords" => Ok(Self::AhkChords),
+            "ahk-shorthands" => Ok(Self::AhkShorthands),
+            "plover" => Ok(Self::Plover),
+            "espanso" => Ok(Self::Espanso),
+            "csv" => Ok(Self::Csv),
+            other => bail!("Unknown format `{}` (expected ahk-chords, ahk-shorthands, plover, espanso or csv)", other),
+        }
+    }
+}
+
+/// How characters and steno keys map to keyboard keys
+#[derive(Debug, Clone, Default)]
+pub struct ConvertOptions {
+    /// Maps the characters of character-based dictionaries
+    pub layout: Layout,
+    /// Maps the steno keys of Plover dictionaries
+    pub steno: StenoMapping,
+}
+
+/// Import a dictionary
+pub fn import(format: Format, path: &Path, options: &ConvertOptions) -> Result<(ChordLibrary, Diagnostics)> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    // Windows tools like to start files with a byte order mark
+    let content = content.strip_prefix('\u{feff}').unwrap_or(&content);
+
+    Ok(match format {
+        Format::AhkChords => ahk::import_chords(path, content, &options.layout),
+        Format::AhkShorthands => ahk::import_shorthands(path, content),
+        Format::Plover => plover::import(path, content, &options.steno),
+        Format::Espanso => espanso::import(path, content),
+        Format::Csv => csv::import(path, content, &options.layout),
+    })
+}
+
+/// Export a library, returning the dictionary text and the entries that
+/// couldn't be converted
+pub fn export(format: Format, library: &ChordLibrary, options: &ConvertOptions) -> Result<(String, Diagnostics)> {
+    match format {
+        Format::AhkChords | Format::AhkShorthands => bail!("Exporting to AutoHotkey ZipChord is not supported"),
+        Format::Espanso => bail!("Exporting to espanso is not supported"),
+        Format::Plover => Ok(plover::export(library, &options.steno)),
+        Format::Csv => Ok(csv::export(library, &options.layout)),
+    }
+}
+
+/// Collects imported entries into a library, keeping the first definition
+/// of each chord or sequence
+pub(crate) struct Importer {
+    file: Arc<Path>,
+    library: ChordLibrary,
+    diagnostics: Diagnostics,
+    // Single-stroke entries of every section share one namespace, as in `.zc` files
+    defined: HashMap<Vec<ChordKey>, usize>,
+    shorthands: HashMap<String, usize>,
+}
+
+impl Importer {
+    pub(crate) fn new(file: &Path) -> Self {
+        Self {
+            file: file.into(),
+            library: ChordLibrary::default(),
+            diagnostics: Diagnostics::default(),
+            defined: HashMap::new(),
+            shorthands: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn report(&mut self, severity: Severity, line: usize, column: usize, message: String) {
+        self.diagnostics.push(Diagnostic {
+            file: self.file.to_path_buf(),
+            line,
+            column,
+            severity,
+            message,
+        });
+    }
+
+    /// Add an entry to the section matching its glue: prefixes glue right,
+    /// suffixes left, infixes both ways, and several strokes make a sequence
+    pub(crate) fn insert(&mut self, strokes: Vec<ChordKey>, mut expansion: Expansion, line: usize) {
+        if !self.writable(&expansion, line) {
+            return;
+        }
+        if let Some(&previous) = self.defined.get(&strokes) {
+            self.report(Severity::Warning, line, 1,
+                format!("Already defined on line {}, keeping that definition", previous));
+            return;
+        }
+        expansion.source = Some(Source { file: self.file.clone(), line });
+
+        let section = match (strokes.len(), expansion.attach_left, expansion.attach_right) {
+            (2.., _, _) => Section::Exception,
+            (_, true, true) => Section::Infix,
+            (_, true, false) => Section::Suffix,
+            (_, false, true) => Section::Prefix,
+            (_, false, false) => Section::Chord,
+        };
+        match self.library.insert(section, &strokes, expansion) {
+            Ok(_) => { self.defined.insert(strokes, line); }
+            Err(e) => self.report(Severity::Error, line, 1, e.to_string()),
+        }
+    }
+
+    /// Add a `[shorthands]` entry expanding a typed abbreviation
+    pub(crate) fn insert_shorthand(&mut self, abbreviation: &str, mut expansion: Expansion, line: usize) {
+        if !self.writable(&expansion, line) {
+            return;
+        }
+        if let Some(&previous) = self.shorthands.get(abbreviation) {
+            self.report(Severity::Warning, line, 1,
+                format!("Already defined on line {}, keeping that definition", previous));
+            return;
+        }
+        expansion.source = Some(Source { file: self.file.clone(), line });
+
+        match self.library.insert_shorthand(abbreviation, expansion) {
+            Ok(_) => { self.shorthands.insert(abbreviation.to_string(), line); }
+            Err(e) => self.report(Severity::Error, line, 1, e.to_string()),
+        }
+    }
+
+    // The library must read back as imported, see `Expansion::to_value`
+    fn writable(&mut self, expansion: &Expansion, line: usize) -> bool {
+        match expansion.check_writable() {
+            Ok(()) => true,
+            Err(e) => {
+                self.report(Severity::Error, line, 1, e.to_string());
+                false
+            }
+        }
+    }
+
+    pub(crate) fn finish(self) -> (ChordLibrary, Diagnostics) {
+        (self.library, self.diagnostics)
+    }
+}
+
+/// Reports entries that can't be exported at the line that defined them
+#[derive(Default)]
+pub(crate) struct Exporter {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Exporter {
+    pub(crate) fn report(&mut self, expansion: &Expansion, severity: Severity, message: String) {
+        let (file, line) = match &expansion.source {
+            Some(source) => (source.file.to_path_buf(), source.line),
+            None => (PathBuf::new(), 0),
+        };
+        self.diagnostics.push(Diagnostic { file, line, column: 1, severity, message });
+    }
+
+    /// Report every shorthand of `library`, which `format` has no equivalent for
+    pub(crate) fn skip_shorthands(&mut self, library: &ChordLibrary, format: &str) {
+        for (abbreviation, expansion) in library.shorthands() {
+            self.report(expansion, Severity::Error,
+                format!("Shorthand `{}` can't be written to {}", abbreviation, format));
+        }
+    }
+
+    /// The reported diagnostics in source order
+    pub(crate) fn finish(mut self) -> Diagnostics {
+        self.diagnostics.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+        let mut diagnostics = Diagnostics::default();
+        for diagnostic in self.diagnostics {
+            diagnostics.push(diagnostic);
+        }
+        diagnostics
+    }
+}
+
+/// Every entry of a library as strokes and expansion, sorted by strokes
+pub(crate) fn entries(library: &ChordLibrary) -> Vec<(Vec<ChordKey>, &Expansion)> {
+    let mut entries: Vec<_> = Section::ALL.into_iter()
+        .flat_map(|section| library.section(section))
+        .map(|(chord, expansion)| (vec![*chord], expansion))
+        .chain(library.sequences().iter().map(|sequence| (sequence.strokes.clone(), &sequence.expansion)))
+        .collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    entries
+}
+
+/// The chord pressing the keys that type each character of `chars` on
+/// `layout`. Errors say which character can't be chorded.
+pub(crate) fn chord_of(chars: &str, layout: &Layout) -> Result<ChordKey, String> {
+    let keys = chars.chars()
+        .map(|c| match layout.keystroke(c) {
+            Some(keystroke) if keystroke.shift => {
+                Err(format!("`{}` needs Shift on the {} layout, which a chord can't hold", c, layout.name()))
+            }
+            Some(keystroke) => Ok(keystroke.key),
+            None => Err(format!("No key types `{}` on the {} layout", c, layout.name())),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    ChordKey::new(&keys).ok_or_else(|| format!("Chord has more than {} keys", MAX_CHORD_KEYS))
+}
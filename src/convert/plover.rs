@@ -0,0 +1,498 @@
+//! Plover JSON steno dictionaries.
+//!
+//! A dictionary maps outlines such as `KAT` or `PHRAOEUPB/-G` to
+//! translations. Each stroke of an outline becomes a chord by pressing the
+//! keyboard keys a [`StenoMapping`] assigns to its steno keys; outlines of
+//! several strokes become `[exceptions]` sequences. Outlines of one stroke
+//! pressing a single key, such as `S` or `-G`, are reported and skipped.
+//!
+//! Translations may glue with `{^}`, `{^ing}`, `{re^}` and `{^-^}`, and
+//! use the punctuation commands `{.}`, `{,}`, `{?}`, `{!}`, `{:}` and `{;}`,
+//! which attach to the previous word. Other Plover commands are reported.
+
+use anyhow::{bail, Context, Result};
+use evdev::KeyCode;
+use serde_json::{Map, Value};
+use std::{collections::BTreeMap, fs, ops::RangeInclusive, path::Path};
+
+use super::{entries, Exporter, Importer};
+use crate::library::{parse_key, ChordKey, ChordLibrary, Diagnostics, Expansion, Severity, MAX_CHORD_KEYS};
+
+/// Steno keys of the English stenotype in steno order
+const STENO_KEYS: [&str; 23] = [
+    "#", "S-", "T-", "K-", "P-", "W-", "H-", "R-", "A-", "O-", "*",
+    "-E", "-U", "-F", "-R", "-P", "-B", "-L", "-G", "-T", "-S", "-D", "-Z",
+];
+
+/// Index of `-E`, where a `-` in an outline skips to
+const RIGHT_HAND: usize = 11;
+
+/// Indexes of `A-` to `-U`, the vowels and `*` between the hands
+const VOWELS: RangeInclusive<usize> = 8..=12;
+
+/// Steno keys written as digits when the number key is held
+const DIGITS: [(char, &str); 10] = [
+    ('1', "S-"), ('2', "T-"), ('3', "P-"), ('4', "H-"), ('5', "A-"),
+    ('0', "O-"), ('6', "-F"), ('7', "-P"), ('8', "-L"), ('9', "-T"),
+];
+
+/// Punctuation commands that attach to the previous word
+const PUNCTUATION: [&str; 6] = [".", ",", "?", "!", ":", ";"];
+
+/// Plover's default keymap for a regular QWERTY keyboard
+const PLOVER_QWERTY: [(&str, &[&str]); 23] = [
+    ("#", &["1", "2", "3", "4", "5", "6", "7", "8", "9", "0", "-", "="]),
+    ("S-", &["a", "q"]),
+    ("T-", &["w"]),
+    ("K-", &["s"]),
+    ("P-", &["e"]),
+    ("W-", &["d"]),
+    ("H-", &["r"]),
+    ("R-", &["f"]),
+    ("A-", &["c"]),
+    ("O-", &["v"]),
+    ("*", &["t", "g", "y", "h"]),
+    ("-E", &["n"]),
+    ("-U", &["m"]),
+    ("-F", &["u"]),
+    ("-R", &["j"]),
+    ("-P", &["i"]),
+    ("-B", &["k"]),
+    ("-L", &["o"]),
+    ("-G", &["l"]),
+    ("-T", &["p"]),
+    ("-S", &[";"]),
+    ("-D", &["["]),
+    ("-Z", &["'"]),
+];
+
+/// Which keyboard keys play each steno key.
+///
+/// Importing presses the first key listed for a steno key; exporting
+/// accepts any of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StenoMapping {
+    keys: [Vec<KeyCode>; STENO_KEYS.len()],
+}
+
+impl StenoMapping {
+    /// Plover's default keymap for a QWERTY keyboard
+    pub fn plover_qwerty() -> Self {
+        let mut mapping = Self { keys: Default::default() };
+        for (steno, keys) in PLOVER_QWERTY {
+            mapping.set(steno, keys.iter().copied()).expect("built-in keymap is valid");
+        }
+        mapping
+    }
+
+    /// Read a keymap in the shape Plover stores them, a JSON object of
+    /// steno keys to key names: `{"S-": ["a", "q"], "T-": ["w"], ...}`.
+    /// Steno keys it doesn't list keep their default keys.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let keymap: Map<String, Value> = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse steno keymap {}", path.display()))?;
+
+        let mut mapping = Self::plover_qwerty();
+        for (steno, keys) in &keymap {
+            // Plover keymaps also bind keys to its own actions
+            if steno == "no-op" || steno == "arpeggiate" {
+                continue;
+            }
+            let Some(keys) = keys.as_array() else {
+                bail!("Keys for `{}` in {} must be a list", steno, path.display());
+            };
+            let names = keys.iter().map(|key| key.as_str().unwrap_or_default());
+            mapping.set(steno, names)
+                .with_context(|| format!("Invalid steno keymap {}", path.display()))?;
+        }
+        Ok(mapping)
+    }
+
+    fn set<'a>(&mut self, steno: &str, names: impl Iterator<Item = &'a str>) -> Result<()> {
+        let Some(index) = STENO_KEYS.iter().position(|key| *key == steno) else {
+            bail!("Unknown steno key `{}`", steno);
+        };
+        self.keys[index] = names
+            .map(|name| parse_key(name).with_context(|| format!("Unknown key name `{}` for `{}`", name, steno)))
+            .collect::<Result<_>>()?;
+        Ok(())
+    }
+
+    // The steno key a keyboard key plays
+    fn steno_key(&self, key: KeyCode) -> Option<usize> {
+        self.keys.iter().position(|keys| keys.contains(&key))
+    }
+}
+
+impl Default for StenoMapping {
+    fn default() -> Self {
+        Self::plover_qwerty()
+    }
+}
+
+/// Import a dictionary
+pub fn import(path: &Path, content: &str, mapping: &StenoMapping) -> (ChordLibrary, Diagnostics) {
+    let mut importer = Importer::new(path);
+
+    let dictionary: Map<String, Value> = match serde_json::from_str(content) {
+        Ok(dictionary) => dictionary,
+        Err(e) => {
+            importer.report(Severity::Error, e.line(), e.column(), format!("Invalid Plover dictionary: {}", e));
+            return importer.finish();
+        }
+    };
+
+    // Plover writes one entry per line, so each key is found after the previous one
+    let mut offset = 0;
+    for (outline, value) in &dictionary {
+        let quoted = serde_json::to_string(outline).expect("strings serialize");
+        offset = content[offset..].find(&quoted).map_or(offset, |at| offset + at);
+        let line_start = content[..offset].rfind('\n').map_or(0, |at| at + 1);
+        let number = content[..offset].matches('\n').count() + 1;
+        let column = offset - line_start + 1;
+
+        let Some(value) = value.as_str() else {
+            importer.report(Severity::Error, number, column,
+                format!("Translation of `{}` is not a string", outline));
+            continue;
+        };
+
+        let strokes = outline.split('/')
+            .map(|stroke| parse_stroke(stroke, mapping))
+            .collect::<Result<Vec<_>, _>>();
+        let strokes = match strokes {
+            Ok(strokes) => strokes,
+            Err(message) => {
+                importer.report(Severity::Error, number, column, message);
+                continue;
+            }
+        };
+        // A chord of one key would expand every time that key is typed
+        if let [stroke] = strokes.as_slice() {
+            if stroke.len() == 1 {
+                importer.report(Severity::Error, number, column,
+                    format!("Outline `{}` presses a single key, which would replace typing it", outline));
+                continue;
+            }
+        }
+
+        match translation(value) {
+            Ok(expansion) => importer.insert(strokes, expansion, number),
+            Err(message) => importer.report(Severity::Error, number, column, message),
+        }
+    }
+
+    importer.finish()
+}
+
+/// Export a library as a dictionary, one entry per line as Plover writes them
+pub fn export(library: &ChordLibrary, mapping: &StenoMapping) -> (String, Diagnostics) {
+    let mut exporter = Exporter::default();
+    let mut dictionary = BTreeMap::new();
+    exporter.skip_shorthands(library, "a steno dictionary");
+
+    for (strokes, expansion) in entries(library) {
+        let outline = strokes.iter()
+            .map(|stroke| write_stroke(stroke, mapping))
+            .collect::<Result<Vec<_>, _>>()
+            .map(|strokes| strokes.join("/"));
+        let translation = outline.and_then(|outline| Ok((outline, write_translation(expansion)?)));
+
+        match translation {
+            Ok((outline, translation)) => {
+                if dictionary.contains_key(&outline) {
+                    exporter.report(expansion, Severity::Warning,
+                        format!("Outline `{}` is already used, keeping the first entry", outline));
+                    continue;
+                }
+                dictionary.insert(outline, translation);
+            }
+            Err(message) => exporter.report(expansion, Severity::Error, message),
+        }
+    }
+
+    let lines: Vec<String> = dictionary.iter()
+        .map(|(outline, translation)| format!(
+            "{}: {}",
+            serde_json::to_string(outline).expect("strings serialize"),
+            serde_json::to_string(translation).expect("strings serialize"),
+        ))
+        .collect();
+    (format!("{{\n{}\n}}\n", lines.join(",\n")), exporter.finish())
+}
+
+// Parse one stroke such as `KAT`, `-G` or `1-9` into the chord playing it
+fn parse_stroke(stroke: &str, mapping: &StenoMapping) -> Result<ChordKey, String> {
+    let invalid = || format!("Invalid steno stroke `{}`", stroke);
+    let mut pressed = Vec::new();
+    let mut next = 0;
+
+    for c in stroke.chars() {
+        if c == '-' {
+            if next > RIGHT_HAND {
+                return Err(invalid());
+            }
+            next = RIGHT_HAND;
+            continue;
+        }
+
+        let mut buffer = [0; 4];
+        let (letter, number) = match DIGITS.iter().find(|(digit, _)| *digit == c) {
+            Some((_, steno)) => (steno.trim_matches('-'), true),
+            None => (&*c.encode_utf8(&mut buffer), false),
+        };
+        // Keys come in steno order, so look for the letter after the previous key
+        let offset = STENO_KEYS[next..].iter()
+            .position(|key| key.trim_matches('-') == letter)
+            .ok_or_else(invalid)?;
+        pressed.push(next + offset);
+        if number {
+            pressed.push(0);
+        }
+        next += offset + 1;
+    }
+
+    let keys = pressed.iter()
+        .map(|&steno| mapping.keys[steno].first().copied()
+            .ok_or_else(|| format!("No key plays `{}` in the steno mapping", STENO_KEYS[steno])))
+        .collect::<Result<Vec<_>, _>>()?;
+    ChordKey::new(&keys).ok_or_else(|| format!(
+        "Stroke `{}` needs more than {} keys", stroke, MAX_CHORD_KEYS))
+}
+
+// Write the chord as a stroke in steno order, with `-` before right-hand
+// keys when no vowel or `*` separates them
+fn write_stroke(chord: &ChordKey, mapping: &StenoMapping) -> Result<String, String> {
+    let mut steno = chord.keys()
+        .map(|key| mapping.steno_key(key).ok_or_else(|| format!("`{:?}` plays no steno key in the mapping", key)))
+        .collect::<Result<Vec<_>, _>>()?;
+    steno.sort_unstable();
+    steno.dedup();
+
+    let hyphen = !steno.iter().any(|key| VOWELS.contains(key));
+    let mut stroke = String::new();
+    for (i, &key) in steno.iter().enumerate() {
+        let first_right = key > *VOWELS.end() && (i == 0 || steno[i - 1] <= *VOWELS.end());
+        if hyphen && first_right {
+            stroke.push('-');
+        }
+        stroke.push_str(STENO_KEYS[key].trim_matches('-'));
+    }
+    Ok(stroke)
+}
+
+// Read a translation into an expansion, resolving glue and punctuation
+fn translation(value: &str) -> Result<Expansion, String> {
+    let mut expansion = Expansion::parse("");
+    let mut text = value;
+
+    if let Some(rest) = text.strip_prefix("{^}") {
+        expansion.attach_left = true;
+        text = rest;
+    }
+    if let Some(rest) = text.strip_suffix("{^}") {
+        expansion.attach_right = true;
+        text = rest;
+    }
+    if let Some(mut inner) = text.strip_prefix('{').and_then(|rest| rest.strip_suffix('}')) {
+        if !inner.contains(['{', '}']) {
+            if PUNCTUATION.contains(&inner) {
+                expansion.attach_left = true;
+            } else {
+                let glued = inner.starts_with('^') || inner.ends_with('^');
+                if let Some(rest) = inner.strip_prefix('^') {
+                    expansion.attach_left = true;
+                    inner = rest;
+                }
+                if let Some(rest) = inner.strip_suffix('^') {
+                    expansion.attach_right = true;
+                    inner = rest;
+                }
+                if !glued {
+                    return Err(format!("Plover command `{}` has no zipchord equivalent", text));
+                }
+            }
+            text = inner;
+        }
+    }
+
+    if let Some(start) = text.find(['{', '}']) {
+        let end = text[start..].find('}').map_or(text.len(), |end| start + end + 1);
+        return Err(format!("Plover command `{}` has no zipchord equivalent", &text[start..end]));
+    }
+    if text.is_empty() {
+        return Err(format!("Translation `{}` types no text", value));
+    }
+
+    expansion.text = text.to_string();
+    Ok(expansion)
+}
+
+fn write_translation(expansion: &Expansion) -> Result<String, String> {
+    if expansion.text.contains(['{', '}']) {
+        return Err(format!("`{}` has braces, which Plover reads as commands", expansion.text));
+    }
+
+    Ok(match (expansion.attach_left, expansion.attach_right) {
+        (false, false) => expansion.text.clone(),
+        (true, false) => format!("{{^{}}}", expansion.text),
+        (false, true) => format!("{{{}^}}", expansion.text),
+        (true, true) => format!("{{^{}^}}", expansion.text),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::Section;
+
+    fn reports(diagnostics: &Diagnostics) -> Vec<(usize, Severity, &str)> {
+        diagnostics.iter()
+            .map(|d| (d.line, d.severity, d.message.as_str()))
+            .collect()
+    }
+
+    fn chord(keys: &[KeyCode]) -> ChordKey {
+        ChordKey::new(keys).unwrap()
+    }
+
+    #[test]
+    fn strokes_map_to_keys_and_back() {
+        let mapping = StenoMapping::default();
+        let cases: [(&str, &[KeyCode]); 5] = [
+            ("KAT", &[KeyCode::KEY_S, KeyCode::KEY_C, KeyCode::KEY_P]),
+            ("STK*", &[KeyCode::KEY_A, KeyCode::KEY_W, KeyCode::KEY_S, KeyCode::KEY_T]),
+            ("-FRB", &[KeyCode::KEY_U, KeyCode::KEY_J, KeyCode::KEY_K]),
+            ("TPH-P", &[KeyCode::KEY_W, KeyCode::KEY_E, KeyCode::KEY_R, KeyCode::KEY_I]),
+            ("#S", &[KeyCode::KEY_1, KeyCode::KEY_A]),
+        ];
+        for (stroke, keys) in cases {
+            assert_eq!(parse_stroke(stroke, &mapping), Ok(chord(keys)), "{}", stroke);
+            assert_eq!(write_stroke(&chord(keys), &mapping).as_deref(), Ok(stroke));
+        }
+
+        // Digits hold the number key, and any key listed for a steno key plays it
+        assert_eq!(parse_stroke("1", &mapping), parse_stroke("#S", &mapping));
+        assert_eq!(write_stroke(&chord(&[KeyCode::KEY_Q, KeyCode::KEY_H, KeyCode::KEY_L]), &mapping).as_deref(), Ok("S*G"));
+
+        for stroke in ["KAXT", "TK-A", "-E-"] {
+            assert_eq!(parse_stroke(stroke, &mapping), Err(format!("Invalid steno stroke `{}`", stroke)));
+        }
+        assert_eq!(parse_stroke("PHRAOEUPB", &mapping), Err("Stroke `PHRAOEUPB` needs more than 8 keys".to_string()));
+        assert_eq!(write_stroke(&chord(&[KeyCode::KEY_F13, KeyCode::KEY_A]), &mapping),
+            Err("`KEY_F13` plays no steno key in the mapping".to_string()));
+    }
+
+    #[test]
+    fn keymaps_override_the_default_keys() {
+        let dir = std::env::temp_dir().join(format!("zipchord-plover-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("keymap.json");
+
+        fs::write(&path, r#"{"S-": ["z", "a"], "no-op": ["b"], "-Z": []}"#).unwrap();
+        let mapping = StenoMapping::load(&path).unwrap();
+        assert_eq!(parse_stroke("SKAT", &mapping), Ok(chord(&[KeyCode::KEY_Z, KeyCode::KEY_S, KeyCode::KEY_C, KeyCode::KEY_P])));
+        assert_eq!(write_stroke(&chord(&[KeyCode::KEY_A, KeyCode::KEY_L]), &mapping).as_deref(), Ok("S-G"));
+        assert_eq!(parse_stroke("-Z", &mapping), Err("No key plays `-Z` in the steno mapping".to_string()));
+
+        fs::write(&path, r#"{"X-": ["a"]}"#).unwrap();
+        assert!(format!("{:#}", StenoMapping::load(&path).unwrap_err()).contains("Unknown steno key `X-`"));
+        fs::write(&path, r#"{"S-": ["nope"]}"#).unwrap();
+        assert!(format!("{:#}", StenoMapping::load(&path).unwrap_err()).contains("Unknown key name `nope` for `S-`"));
+        fs::write(&path, r#"{"S-": "a"}"#).unwrap();
+        assert!(StenoMapping::load(&path).unwrap_err().to_string().contains("must be a list"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn translations_are_imported_with_their_glue() {
+        let content = [
+            "{",
+            r#""KAT": "cat","#,
+            r#""STK*": "{^ing}","#,
+            r#""-FRB": "{re^}","#,
+            r#""HEL/HRO": "hello","#,
+            r#""TPH-P": "{^-^}","#,
+            r#""P-P": "{.}","#,
+            r#""1": "{^}","#,
+            r#""-G": "ing","#,
+            r#""KAXT": "x","#,
+            r#""PHRAOEUPB": "plain","#,
+            r#""TKPWHR": "{PLOVER:TOGGLE}","#,
+            r#""TKPWHRA": "go {#Return}","#,
+            r#""SKWR": " leading","#,
+            r#""SKWRA": "{^} glued","#,
+            r#""TKPW": 3,"#,
+            r#""KAT/KAT": "{^}trailing ""#,
+            "}",
+        ].join("\n");
+        let (library, diagnostics) = import(Path::new("main.json"), &content, &StenoMapping::default());
+
+        assert_eq!(reports(&diagnostics), [
+            (8, Severity::Error, "Translation `{^}` types no text"),
+            (9, Severity::Error, "Outline `-G` presses a single key, which would replace typing it"),
+            (10, Severity::Error, "Invalid steno stroke `KAXT`"),
+            (11, Severity::Error, "Stroke `PHRAOEUPB` needs more than 8 keys"),
+            (12, Severity::Error, "Plover command `{PLOVER:TOGGLE}` has no zipchord equivalent"),
+            (13, Severity::Error, "Plover command `{#Return}` has no zipchord equivalent"),
+            (14, Severity::Error, "\" leading\" can't be written to a .zc library without glue before it"),
+            (16, Severity::Error, "Translation of `TKPW` is not a string"),
+            (17, Severity::Error, "\"trailing \" can't be written to a .zc library without glue after it"),
+        ]);
+
+        let mapping = StenoMapping::default();
+        let get = |outline: &str| {
+            let strokes: Vec<_> = outline.split('/').map(|stroke| parse_stroke(stroke, &mapping).unwrap()).collect();
+            let (section, expansion) = library.get(&strokes).unwrap();
+            (section, expansion.to_value(), expansion.source.as_ref().unwrap().line)
+        };
+        assert_eq!(get("KAT"), (Section::Chord, "cat".to_string(), 2));
+        assert_eq!(get("STK*"), (Section::Suffix, "~ing".to_string(), 3));
+        assert_eq!(get("-FRB"), (Section::Prefix, "re~".to_string(), 4));
+        assert_eq!(get("HEL/HRO"), (Section::Exception, "hello".to_string(), 5));
+        assert_eq!(get("TPH-P"), (Section::Infix, "~-~".to_string(), 6));
+        assert_eq!(get("P-P"), (Section::Suffix, "~.".to_string(), 7));
+        assert_eq!(get("SKWRA"), (Section::Suffix, "~ glued".to_string(), 15));
+
+        let (_, diagnostics) = import(Path::new("main.json"), "{\n\"KAT\": \"cat\",\n\"TKPW\" \"go\"\n}", &mapping);
+        let [error] = reports(&diagnostics)[..] else { panic!("{:?}", reports(&diagnostics)) };
+        assert_eq!((error.0, error.1), (3, Severity::Error));
+        assert!(error.2.starts_with("Invalid Plover dictionary"));
+    }
+
+    #[test]
+    fn export_writes_what_import_reads() {
+        let content = [
+            r#""-FRB": "{re^}""#,
+            r#""HEL/HRO": "hello""#,
+            r#""KAT": "cat""#,
+            r#""STK*": "{^ing}""#,
+            r#""TPH-P": "{^-^}""#,
+        ].join(",\n");
+        let content = format!("{{\n{}\n}}\n", content);
+        let (library, diagnostics) = import(Path::new("main.json"), &content, &StenoMapping::default());
+        assert_eq!(diagnostics.len(), 0);
+        let (exported, diagnostics) = export(&library, &StenoMapping::default());
+        assert_eq!(diagnostics.len(), 0);
+        assert_eq!(exported, content);
+
+        let library = ChordLibrary::builder()
+            .chord("q+l", "first").unwrap()
+            .chord("a+l", "second").unwrap()
+            .chord("i+k", "{x}").unwrap()
+            .chord("KEY_F13+a", "unmapped").unwrap()
+            .shorthand("btw", "by the way").unwrap()
+            .build();
+        let (exported, diagnostics) = export(&library, &StenoMapping::default());
+        assert_eq!(exported, "{\n\"S-G\": \"first\"\n}\n");
+        assert_eq!(reports(&diagnostics), [
+            (0, Severity::Error, "Shorthand `btw` can't be written to a steno dictionary"),
+            (0, Severity::Error, "`{x}` has braces, which Plover reads as commands"),
+            (0, Severity::Warning, "Outline `S-G` is already used, keeping the first entry"),
+            (0, Severity::Error, "`KEY_F13` plays no steno key in the mapping"),
+        ]);
+    }
+}
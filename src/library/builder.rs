@@ -0,0 +1,85 @@
+use anyhow::{anyhow, bail, Result};
+
+use super::chord::join_strokes;
+use super::parser::parse_strokes;
+use super::{ChordKey, ChordLibrary, Expansion, Layout, Section, FORMAT_VERSION};
+
+/// Builds a [`ChordLibrary`] in code, with the same checks as a `.zc` file.
+///
+/// Entries are written as in a `.zc` file: `t+h` or `KEY_T+KEY_H` for a
+/// chord, strokes separated by spaces for an `[exceptions]` sequence, and
+/// `~` glue markers in the text. Unlike a file, defining the same strokes
+/// twice is an error.
+#[derive(Debug, Clone)]
+pub struct LibraryBuilder {
+    layout: Layout,
+    library: ChordLibrary,
+}
+
+impl LibraryBuilder {
+    pub fn new() -> Self {
+        let mut library = ChordLibrary::new();
+        library.meta.format_version = FORMAT_VERSION;
+        Self { layout: Layout::default(), library }
+    }
+
+    /// Layout resolving single-character keys in later entries
+    pub fn layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.library.meta.name = name.into();
+        self
+    }
+
+    pub fn language(mut self, language: impl Into<String>) -> Self {
+        self.library.meta.language = language.into();
+        self
+    }
+
+    pub fn version(mut self, version: impl Into<String>) -> Self {
+        self.library.meta.version = version.into();
+        self
+    }
+
+    /// Add an entry written as in a `.zc` file, such as `t+h` and `the`
+    pub fn entry(self, section: Section, keys: &str, text: &str) -> Result<Self> {
+        let strokes = parse_strokes(section, keys, &self.layout).map_err(|(_, message)| anyhow!("`{}`: {}", keys, message))?;
+        self.insert(section, &strokes, Expansion::parse(text))
+    }
+
+    /// Add a chord to `[chords]`
+    pub fn chord(self, keys: &str, text: &str) -> Result<Self> {
+        self.entry(Section::Chord, keys, text)
+    }
+
+    /// Add a shorthand expanding `abbreviation` when a delimiter follows it
+    pub fn shorthand(mut self, abbreviation: &str, text: &str) -> Result<Self> {
+        if self.library.resolve_shorthand(abbreviation).is_some() {
+            bail!("Shorthand `{}` is already defined", abbreviation);
+        }
+        self.library.insert_shorthand(abbreviation, Expansion::parse(text))?;
+        Ok(self)
+    }
+
+    /// Add an entry of physical keys, see [`ChordLibrary::insert`]
+    pub fn insert(mut self, section: Section, strokes: &[ChordKey], expansion: Expansion) -> Result<Self> {
+        if let Some((previous, _)) = self.library.get(strokes) {
+            bail!("`{}` is already defined in [{}]", join_strokes(strokes), previous.name());
+        }
+        self.library.insert(section, strokes, expansion)?;
+        Ok(self)
+    }
+
+    pub fn build(self) -> ChordLibrary {
+        self.library
+    }
+}
+
+impl Default for LibraryBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
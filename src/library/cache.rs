@@ -0,0 +1,502 @@
+//! Compiled `.zcc` cache stored next to a `.zc` library.
+//!
+//! The cache holds the fully resolved library (includes merged, keys
+//! normalised) together with the diagnostics of the parse. It is keyed on
+//! the size, mtime and FNV-1a hash of every source file read, so touching
+//! a file without changing it doesn't force a re-parse.
+//!
+//! Loading reads the file once and copies each string straight into its
+//! entry. Entries are grouped by section behind their count, so every map
+//! is allocated at its final size, and chords are decoded without
+//! allocating. Nothing else is rebuilt: the reverse index behind
+//! [`ChordLibrary::lookup`] is only built when first used.
+
+use anyhow::{ensure, Context, Result};
+use evdev::KeyCode;
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::UNIX_EPOCH,
+};
+
+use super::diagnostics::{Diagnostic, Diagnostics, Severity};
+use super::expansion::{Expansion, Source};
+use super::sequence::Sequence;
+use super::{ChordKey, ChordLibrary, DuplicatePolicy, LoadOptions, Section, MAX_CHORD_KEYS};
+
+pub const CACHE_EXTENSION: &str = "zcc";
+
+const MAGIC: &[u8; 4] = b"ZCC\0";
+const CACHE_VERSION: u32 = 4;
+const NO_SOURCE: u32 = u32::MAX;
+// Size recorded for an include target that doesn't exist
+const ABSENT: u64 = u64::MAX;
+// Order the single-stroke sections are stored in
+const SECTIONS: [Section; 5] = [
+    Section::Chord,
+    Section::Prefix,
+    Section::Suffix,
+    Section::Infix,
+    Section::Exception,
+];
+
+/// Where the cache for `source` lives
+pub fn cache_path(source: &Path) -> PathBuf {
+    source.with_extension(CACHE_EXTENSION)
+}
+
+/// Size, mtime and content hash of a source file when the cache was built
+#[derive(Debug, PartialEq, Eq)]
+struct Fingerprint {
+    size: u64,
+    mtime_nanos: u64,
+    hash: u64,
+}
+
+impl Fingerprint {
+    // A missing file is recorded too, so creating it makes the cache stale
+    fn of(path: &Path) -> Result<Self> {
+        let content = match fs::read(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Ok(Self { size: ABSENT, mtime_nanos: 0, hash: 0 });
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        Ok(Self {
+            hash: fnv1a(&content),
+            ..Self::stat(path)?
+        })
+    }
+
+    // Cheap check without reading the file; `hash` is left empty
+    fn stat(path: &Path) -> Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+        Ok(Self {
+            size: metadata.len(),
+            mtime_nanos: mtime.as_nanos() as u64,
+            hash: 0,
+        })
+    }
+
+    fn is_current(&self, path: &Path) -> bool {
+        if self.size == ABSENT {
+            return fs::metadata(path).is_err_and(|e| e.kind() == ErrorKind::NotFound);
+        }
+        match Self::stat(path) {
+            Ok(now) if now.size == self.size && now.mtime_nanos == self.mtime_nanos => true,
+            // Touched but possibly unchanged
+            Ok(now) if now.size == self.size => Self::of(path).is_ok_and(|now| now.hash == self.hash),
+            _ => false,
+        }
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+// Anything in the options that changes the parsed result, and the crate
+// version, as parsing rules can change between releases without the cache
+// format changing
+fn options_tag(options: &LoadOptions) -> u64 {
+    let mut bytes = env!("CARGO_PKG_VERSION").as_bytes().to_vec();
+    bytes.push(match options.duplicates {
+        DuplicatePolicy::FirstWins => 0,
+        DuplicatePolicy::LastWins => 1,
+        DuplicatePolicy::Error => 2,
+    });
+    for (c, keystroke) in options.layout.sorted_keys() {
+        bytes.extend((c as u32).to_le_bytes());
+        bytes.extend(keystroke.key.code().to_le_bytes());
+        bytes.push(u8::from(keystroke.shift));
+    }
+    fnv1a(&bytes)
+}
+
+/// Load the cache for `source` if it exists and every file it was built
+/// from is unchanged
+pub fn read(source: &Path, options: &LoadOptions) -> Option<(ChordLibrary, Diagnostics)> {
+    let path = cache_path(source);
+    let bytes = fs::read(&path).ok()?;
+    match decode(&bytes, options) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            log::debug!("Ignoring unreadable cache {}: {:#}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Write the cache for `source`, built from the files in `sources`. Include
+/// targets that don't exist may be among them.
+pub fn write(
+    source: &Path,
+    options: &LoadOptions,
+    library: &ChordLibrary,
+    diagnostics: &Diagnostics,
+    sources: &[PathBuf],
+) -> Result<PathBuf> {
+    let mut out = Writer::default();
+    out.bytes(MAGIC);
+    out.u32(CACHE_VERSION);
+    out.u64(options_tag(options));
+
+    out.u32(sources.len() as u32);
+    for file in sources {
+        let fingerprint = Fingerprint::of(file)?;
+        out.path(file);
+        out.u64(fingerprint.size);
+        out.u64(fingerprint.mtime_nanos);
+        out.u64(fingerprint.hash);
+    }
+
+    out.str(&library.meta.name);
+    out.str(&library.meta.language);
+    out.str(&library.meta.version);
+    out.u32(library.meta.format_version);
+
+    // Entries refer to their file by index into this table
+    let mut files: Vec<Arc<Path>> = Vec::new();
+    let mut file_index = |file: &Arc<Path>| match files.iter().position(|f| f == file) {
+        Some(index) => index as u32,
+        None => {
+            files.push(file.clone());
+            files.len() as u32 - 1
+        }
+    };
+
+    let mut entries = Writer::default();
+    for section in SECTIONS {
+        let section = library.section(section);
+        entries.u32(section.len() as u32);
+        for (chord, expansion) in section {
+            entries.chord(chord);
+            entries.expansion(expansion, &mut file_index);
+        }
+    }
+    entries.u32(library.sequences.len() as u32);
+    for sequence in &library.sequences {
+        entries.u8(sequence.strokes.len() as u8);
+        for chord in &sequence.strokes {
+            entries.chord(chord);
+        }
+        entries.expansion(&sequence.expansion, &mut file_index);
+    }
+    entries.u32(library.shorthands.len() as u32);
+    for (abbreviation, expansion) in &library.shorthands {
+        entries.str(abbreviation);
+        entries.expansion(expansion, &mut file_index);
+    }
+
+    out.u32(files.len() as u32);
+    for file in &files {
+        out.path(file);
+    }
+    out.bytes(&entries.buf);
+
+    out.u32(diagnostics.len() as u32);
+    for diagnostic in diagnostics {
+        out.path(&diagnostic.file);
+        out.u32(diagnostic.line as u32);
+        out.u32(diagnostic.column as u32);
+        out.u8(matches!(diagnostic.severity, Severity::Error) as u8);
+        out.str(&diagnostic.message);
+    }
+
+    // Write then rename so a reader never sees a partial cache
+    let path = cache_path(source);
+    let partial = path.with_extension("zcc.partial");
+    fs::write(&partial, &out.buf).with_context(|| format!("Failed to write {}", partial.display()))?;
+    fs::rename(&partial, &path).with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(path)
+}
+
+fn decode(bytes: &[u8], options: &LoadOptions) -> Result<Option<(ChordLibrary, Diagnostics)>> {
+    let mut input = Reader { bytes };
+    ensure!(input.take(MAGIC.len())? == MAGIC, "not a library cache");
+    ensure!(input.u32()? == CACHE_VERSION, "cache version mismatch");
+    if input.u64()? != options_tag(options) {
+        return Ok(None);
+    }
+
+    for _ in 0..input.u32()? {
+        let file = input.path()?;
+        let fingerprint = Fingerprint {
+            size: input.u64()?,
+            mtime_nanos: input.u64()?,
+            hash: input.u64()?,
+        };
+        if !fingerprint.is_current(&file) {
+            return Ok(None);
+        }
+    }
+
+    let mut library = ChordLibrary::default();
+    library.meta.name = input.str()?.to_string();
+    library.meta.language = input.str()?.to_string();
+    library.meta.version = input.str()?.to_string();
+    library.meta.format_version = input.u32()?;
+
+    let files = (0..input.u32()?)
+        .map(|_| input.path().map(Arc::from))
+        .collect::<Result<Vec<Arc<Path>>>>()?;
+
+    for section in SECTIONS {
+        let count = input.u32()? as usize;
+        let entries = library.section_mut(section);
+        entries.reserve(count);
+        for _ in 0..count {
+            let chord = input.chord()?;
+            entries.insert(chord, input.expansion(&files)?);
+        }
+    }
+
+    let count = input.u32()? as usize;
+    library.sequences.reserve(count);
+    for _ in 0..count {
+        let strokes = (0..input.u8()?)
+            .map(|_| input.chord())
+            .collect::<Result<Vec<_>>>()?;
+        ensure!(strokes.len() > 1, "sequence of fewer than two strokes");
+        let expansion = input.expansion(&files)?;
+        library.sequences.push(Sequence { strokes, expansion });
+    }
+
+    let count = input.u32()? as usize;
+    library.shorthands.reserve(count);
+    for _ in 0..count {
+        let abbreviation = input.str()?.to_string();
+        let expansion = input.expansion(&files)?;
+        library.shorthands.insert(abbreviation, expansion);
+    }
+
+    let mut diagnostics = Diagnostics::default();
+    for _ in 0..input.u32()? {
+        diagnostics.push(Diagnostic {
+            file: input.path()?,
+            line: input.u32()? as usize,
+            column: input.u32()? as usize,
+            severity: if input.u8()? == 1 { Severity::Error } else { Severity::Warning },
+            message: input.str()?.to_string(),
+        });
+    }
+    ensure!(input.bytes.is_empty(), "trailing bytes after the cache");
+
+    Ok(Some((library, diagnostics)))
+}
+
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn str(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.bytes(value.as_bytes());
+    }
+
+    fn path(&mut self, path: &Path) {
+        self.str(&path.to_string_lossy());
+    }
+
+    fn chord(&mut self, chord: &ChordKey) {
+        self.u8(chord.len() as u8);
+        for key in chord.keys() {
+            self.u16(key.code());
+        }
+    }
+
+    fn expansion(&mut self, expansion: &Expansion, file_index: &mut impl FnMut(&Arc<Path>) -> u32) {
+        self.u8(u8::from(expansion.attach_left) | u8::from(expansion.attach_right) << 1);
+        self.str(&expansion.text);
+        match &expansion.source {
+            Some(source) => {
+                self.u32(file_index(&source.file));
+                self.u32(source.line as u32);
+            }
+            None => {
+                self.u32(NO_SOURCE);
+                self.u32(0);
+            }
+        }
+    }
+}
+
+/// Cursor over the cache bytes; strings are borrowed until copied into entries
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        ensure!(self.bytes.len() >= len, "truncated cache");
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn str(&mut self) -> Result<&'a str> {
+        let len = self.u32()? as usize;
+        Ok(std::str::from_utf8(self.take(len)?)?)
+    }
+
+    fn path(&mut self) -> Result<PathBuf> {
+        Ok(PathBuf::from(self.str()?))
+    }
+
+    fn chord(&mut self) -> Result<ChordKey> {
+        let len = usize::from(self.u8()?);
+        ensure!(len <= MAX_CHORD_KEYS, "chord too long");
+        let mut keys = [KeyCode::new(0); MAX_CHORD_KEYS];
+        for key in &mut keys[..len] {
+            *key = KeyCode::new(self.u16()?);
+        }
+        ChordKey::new(&keys[..len]).context("chord too long")
+    }
+
+    fn expansion(&mut self, files: &[Arc<Path>]) -> Result<Expansion> {
+        let flags = self.u8()?;
+        let text = self.str()?.to_string();
+        let file = self.u32()?;
+        let line = self.u32()? as usize;
+        let source = match file {
+            NO_SOURCE => None,
+            index => Some(Source {
+                file: files.get(index as usize).context("bad file index")?.clone(),
+                line,
+            }),
+        };
+        Ok(Expansion {
+            text,
+            attach_left: flags & 1 != 0,
+            attach_right: flags & 2 != 0,
+            source,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIBRARY: &str = concat!(
+        "ZIPCHORD LIBRARY v1\n",
+        "name: Test\n",
+        "[chords]\n",
+        "t+h => the\n",
+        "t+zz => broken\n",
+        "[suffixes]\n",
+        "i+n+g => ing\n",
+        "[exceptions]\n",
+        "e . g . => e.g.\n",
+        "[shorthands]\n",
+        "btw => by the way~\n",
+    );
+
+    #[test]
+    fn round_trip() {
+        let dir = std::env::temp_dir().join(format!("zipchord-cache-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("test.zc");
+        fs::write(&source, LIBRARY).unwrap();
+        let options = LoadOptions { cache: true, ..Default::default() };
+
+        let (parsed, reported) = ChordLibrary::load_with(&source, &options).unwrap();
+        let (cached, cached_reported) = read(&source, &options).expect("the cache is current");
+
+        assert_eq!(cached.to_document().unwrap().to_string(), parsed.to_document().unwrap().to_string());
+        assert_eq!(cached.meta().name, "Test");
+        assert_eq!(cached_reported, reported);
+        assert_eq!(cached.lookup("the"), parsed.lookup("the"));
+        assert_eq!(cached.lookup("by the way"), parsed.lookup("by the way"));
+
+        // Other options or an edited source make the cache stale
+        let first_wins = LoadOptions { duplicates: DuplicatePolicy::FirstWins, ..options.clone() };
+        assert!(read(&source, &first_wins).is_none());
+        fs::write(&source, LIBRARY.replace("the", "then")).unwrap();
+        assert!(read(&source, &options).is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_include_is_rechecked() {
+        let dir = std::env::temp_dir().join(format!("zipchord-cache-include-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("main.zc");
+        fs::write(&source, "ZIPCHORD LIBRARY v1\ninclude: extra.zc\n[chords]\nt+h => the\n").unwrap();
+        let options = LoadOptions { cache: true, ..Default::default() };
+
+        let (_, reported) = ChordLibrary::load_with(&source, &options).unwrap();
+        assert!(reported.has_errors());
+        assert!(read(&source, &options).is_some());
+
+        fs::write(dir.join("extra.zc"), "ZIPCHORD LIBRARY v1\n[chords]\na+n => and\n").unwrap();
+        assert!(read(&source, &options).is_none());
+        let (library, reported) = ChordLibrary::load_with(&source, &options).unwrap();
+        assert!(!reported.has_errors());
+        assert_eq!(library.resolve(&[KeyCode::KEY_A, KeyCode::KEY_N]).unwrap().text, "and");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        let dir = std::env::temp_dir().join(format!("zipchord-cache-trailing-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("test.zc");
+        fs::write(&source, LIBRARY).unwrap();
+        let options = LoadOptions { cache: true, ..Default::default() };
+        ChordLibrary::compile(&source, &options).unwrap();
+
+        let mut bytes = fs::read(cache_path(&source)).unwrap();
+        assert!(decode(&bytes, &options).is_ok());
+        bytes.push(0);
+        assert!(decode(&bytes, &options).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+use evdev::KeyCode;
+use std::fmt;
+
+/// Most keys a chord can hold, the same limit `ChordStream` detects
+pub const MAX_CHORD_KEYS: usize = 8;
+
+/// A set of keys stored as a sorted fixed-size array of key codes.
+///
+/// Building one from a detected chord doesn't allocate, so it can be used
+/// directly as the lookup key on the key-release path.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ChordKey {
+    len: u8,
+    codes: [u16; MAX_CHORD_KEYS],
+}
+
+impl ChordKey {
+    /// Build a chord from keys in any order, ignoring repeated keys.
+    /// Returns `None` when there are more than [`MAX_CHORD_KEYS`] distinct keys.
+    pub fn new(keys: &[KeyCode]) -> Option<Self> {
+        let mut chord = Self {
+            len: 0,
+            codes: [0; MAX_CHORD_KEYS],
+        };
+
+        for key in keys {
+            let code = key.code();
+            let len = chord.len as usize;
+            // Insertion sort, chords are tiny
+            let Err(pos) = chord.codes[..len].binary_search(&code) else {
+                continue;
+            };
+            if len == MAX_CHORD_KEYS {
+                return None;
+            }
+            chord.codes.copy_within(pos..len, pos + 1);
+            chord.codes[pos] = code;
+            chord.len += 1;
+        }
+
+        Some(chord)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = KeyCode> + '_ {
+        self.codes[..self.len()].iter().map(|&code| KeyCode::new(code))
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl fmt::Display for ChordKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, key) in self.keys().enumerate() {
+            if i > 0 {
+                f.write_str("+")?;
+            }
+            write!(f, "{:?}", key)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ChordKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ChordKey({})", self)
+    }
+}
+
+/// Strokes of a sequence as written in a `.zc` file, separated by spaces
+pub(crate) fn join_strokes(strokes: &[ChordKey]) -> String {
+    strokes.iter().map(ChordKey::to_string).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_order_and_repeats_do_not_matter() {
+        let th = ChordKey::new(&[KeyCode::KEY_T, KeyCode::KEY_H]).unwrap();
+        let ht = ChordKey::new(&[KeyCode::KEY_H, KeyCode::KEY_T, KeyCode::KEY_H]).unwrap();
+
+        assert_eq!(th, ht);
+        assert_eq!(th.len(), 2);
+        assert_eq!(th.keys().collect::<Vec<_>>(), [KeyCode::KEY_T, KeyCode::KEY_H]);
+        assert_eq!(th.to_string(), "KEY_T+KEY_H");
+    }
+
+    #[test]
+    fn too_many_keys_are_rejected() {
+        let keys: Vec<KeyCode> = (KeyCode::KEY_Q.code()..).take(MAX_CHORD_KEYS + 1).map(KeyCode::new).collect();
+
+        assert!(ChordKey::new(&keys[..MAX_CHORD_KEYS]).is_some());
+        assert!(ChordKey::new(&keys).is_none());
+    }
+}
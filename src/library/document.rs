@@ -0,0 +1,500 @@
+//! Lossless model of a `.zc` file.
+//!
+//! Every line keeps its original text and line ending, so a document that
+//! isn't edited is written back byte for byte. [`LibraryParser`] reads
+//! libraries through this model, and tools use it to edit files in place.
+//!
+//! [`LibraryParser`]: super::parser::LibraryParser
+
+use anyhow::{bail, ensure, Context, Result};
+use std::{fmt, fs, path::Path};
+
+use super::keys::parse_key;
+use super::{join_strokes, ChordKey, ChordLibrary, Expansion, Section, FORMAT_VERSION};
+
+pub(crate) const HEADER: &str = "ZIPCHORD LIBRARY";
+const META_KEYS: [&str; 4] = ["name", "language", "version", "include"];
+
+/// Byte range within a line's text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn of(self, text: &str) -> &str {
+        &text[self.start..self.end]
+    }
+
+    // Span of `part`, which must be a slice of `text`
+    fn within(text: &str, part: &str) -> Self {
+        let start = part.as_ptr() as usize - text.as_ptr() as usize;
+        Self { start, end: start + part.len() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LineKind {
+    Blank,
+    Comment,
+    /// `ZIPCHORD LIBRARY v1`, spanning the version after the magic words
+    Header { version: Span },
+    /// `name: ...`, `language: ...`, `version: ...` or `include: ...`
+    Meta { key: Span, value: Span },
+    /// `[name]`, spanning the name
+    Section { name: Span },
+    /// `keys => value # comment`
+    Entry { keys: Span, value: Span, comment: Option<Span> },
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    text: String,
+    ending: String,
+    kind: LineKind,
+}
+
+impl Line {
+    fn new(text: String, ending: String) -> Self {
+        let kind = classify(&text);
+        Self { text, ending, kind }
+    }
+
+    /// The line without its line ending
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn kind(&self) -> &LineKind {
+        &self.kind
+    }
+
+    fn set_text(&mut self, text: String) {
+        self.kind = classify(&text);
+        self.text = text;
+    }
+}
+
+fn classify(text: &str) -> LineKind {
+    let line = text.trim();
+
+    if line.is_empty() {
+        return LineKind::Blank;
+    }
+    if line.starts_with('#') {
+        return LineKind::Comment;
+    }
+    if let Some(rest) = line.strip_prefix(HEADER) {
+        return LineKind::Header { version: Span::within(text, rest.trim()) };
+    }
+    // Before metadata, so an entry whose keys start with `name:` stays an entry
+    if let Some((keys, rest)) = line.split_once("=>") {
+        // Everything after `#` is an inline comment
+        let (value, comment) = match rest.find('#') {
+            Some(at) => (&rest[..at], Some(Span::within(text, &rest[at..]))),
+            None => (rest, None),
+        };
+        return LineKind::Entry {
+            keys: Span::within(text, keys.trim()),
+            value: Span::within(text, value.trim()),
+            comment,
+        };
+    }
+    if let Some((key, value)) = line.split_once(':') {
+        if META_KEYS.contains(&key) {
+            return LineKind::Meta {
+                key: Span::within(text, key),
+                value: Span::within(text, value.trim()),
+            };
+        }
+    }
+    if line.starts_with('[') && line.ends_with(']') {
+        return LineKind::Section { name: Span::within(text, &line[1..line.len() - 1]) };
+    }
+
+    LineKind::Other
+}
+
+/// A `.zc` file as editable lines
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Document {
+    lines: Vec<Line>,
+}
+
+impl Document {
+    pub fn parse(content: &str) -> Self {
+        let lines = content
+            .split_inclusive('\n')
+            .map(|line| {
+                let text = line.trim_end_matches(['\n', '\r']);
+                Line::new(text.to_string(), line[text.len()..].to_string())
+            })
+            .collect();
+        Self { lines }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(Self::parse(&content))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_string())
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn lines(&self) -> &[Line] {
+        &self.lines
+    }
+
+    /// Value of a metadata line such as `name:`
+    pub fn meta(&self, key: &str) -> Option<&str> {
+        self.lines.iter().find_map(|line| match line.kind {
+            LineKind::Meta { key: k, value } if k.of(&line.text) == key => Some(value.of(&line.text)),
+            _ => None,
+        })
+    }
+
+    /// Set a metadata line, adding it after the header when missing.
+    /// Fails if the key isn't one of `name`, `language`, `version` or
+    /// `include`, or the value can't be read back from a `.zc` line.
+    pub fn set_meta(&mut self, key: &str, value: &str) -> Result<()> {
+        ensure!(META_KEYS.contains(&key), "Unknown metadata key `{}` (expected {})", key, META_KEYS.join(", "));
+        check_field(value)?;
+        let existing = self.lines.iter().position(|line| {
+            matches!(line.kind, LineKind::Meta { key: k, .. } if k.of(&line.text) == key)
+        });
+        let text = format!("{}: {}", key, value);
+        match existing {
+            Some(index) => self.lines[index].set_text(text),
+            None => {
+                let after = self.lines.iter()
+                    .rposition(|line| matches!(line.kind, LineKind::Header { .. } | LineKind::Meta { .. }))
+                    .map_or(0, |index| index + 1);
+                self.insert(after, text);
+            }
+        }
+        Ok(())
+    }
+
+    /// Value of the entry for `keys` in `section`, in `.zc` syntax
+    pub fn get(&self, section: &str, keys: &str) -> Option<&str> {
+        let index = self.find_entry(section, keys)?;
+        let line = &self.lines[index];
+        match line.kind {
+            LineKind::Entry { value, .. } => Some(value.of(&line.text)),
+            _ => None,
+        }
+    }
+
+    /// Add or replace an entry. A replaced entry keeps its key spelling,
+    /// indentation and inline comment; a new one goes after the last entry
+    /// of the section, which is created at the end of the file if needed.
+    /// Fails if the keys or value can't be read back from a `.zc` line.
+    pub fn set(&mut self, section: &str, keys: &str, value: &str) -> Result<()> {
+        check_field(keys)?;
+        check_writable(value)?;
+        ensure!(value.trim() == value, "{:?} can't be written to a .zc library, whitespace around a value is dropped", value);
+        if let Some(index) = self.find_entry(section, keys) {
+            let line = &self.lines[index];
+            let LineKind::Entry { value: span, .. } = line.kind else {
+                unreachable!("find_entry only returns entries");
+            };
+            let text = format!("{}{}{}", &line.text[..span.start], value, &line.text[span.end..]);
+            self.lines[index].set_text(text);
+            return Ok(());
+        }
+
+        let text = format!("{} => {}", keys, value);
+        match self.section_range(section) {
+            Some((start, end)) => {
+                // After the last non-blank line, so trailing spacing stays in place
+                let after = (start..end).rev()
+                    .find(|&index| self.lines[index].kind != LineKind::Blank)
+                    .map_or(start, |index| index + 1);
+                self.insert(after, text);
+            }
+            None => {
+                if self.lines.last().is_some_and(|line| line.kind != LineKind::Blank) {
+                    self.push(String::new());
+                }
+                self.push(format!("[{}]", section));
+                self.push(text);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove an entry, returning whether it existed
+    pub fn remove(&mut self, section: &str, keys: &str) -> bool {
+        match self.find_entry(section, keys) {
+            Some(index) => {
+                self.lines.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, index: usize, text: String) {
+        let ending = self.ending();
+        // A last line without a newline must get one before anything follows it
+        if index > 0 && self.lines[index - 1].ending.is_empty() {
+            self.lines[index - 1].ending = ending.clone();
+        }
+        self.lines.insert(index, Line::new(text, ending));
+    }
+
+    fn push(&mut self, text: String) {
+        self.insert(self.lines.len(), text);
+    }
+
+    // Line ending used by the file, defaulting to `\n`
+    fn ending(&self) -> String {
+        self.lines.iter()
+            .map(|line| line.ending.as_str())
+            .find(|ending| !ending.is_empty())
+            .unwrap_or("\n")
+            .to_string()
+    }
+
+    // Lines after the first `[section]` header up to the next header
+    fn section_range(&self, section: &str) -> Option<(usize, usize)> {
+        let header = self.lines.iter().position(|line| match line.kind {
+            LineKind::Section { name } => name.of(&line.text).eq_ignore_ascii_case(section),
+            _ => false,
+        })?;
+        let end = self.lines[header + 1..].iter()
+            .position(|line| matches!(line.kind, LineKind::Section { .. }))
+            .map_or(self.lines.len(), |offset| header + 1 + offset);
+        Some((header + 1, end))
+    }
+
+    fn find_entry(&self, section: &str, keys: &str) -> Option<usize> {
+        let wanted = parse_strokes(keys);
+        let mut current: Option<&str> = None;
+
+        self.lines.iter().position(|line| match line.kind {
+            LineKind::Section { name } => {
+                current = Some(name.of(&line.text));
+                false
+            }
+            LineKind::Entry { keys: span, .. } => {
+                current.is_some_and(|name| name.eq_ignore_ascii_case(section))
+                    && same_keys(span.of(&line.text), keys, wanted.as_deref())
+            }
+            _ => false,
+        })
+    }
+}
+
+impl fmt::Display for Document {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            f.write_str(&line.text)?;
+            f.write_str(&line.ending)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check that `text` reads back unchanged from a `.zc` line, where `#`
+/// starts a comment and line breaks end the line
+pub(crate) fn check_writable(text: &str) -> Result<()> {
+    if let Some(c) = text.chars().find(|c| matches!(c, '\n' | '\r' | '#')) {
+        bail!("{:?} can't be written to a .zc library, it contains {:?}", text, c);
+    }
+    Ok(())
+}
+
+// Keys and metadata values also end at `=>` and lose surrounding whitespace
+fn check_field(text: &str) -> Result<()> {
+    check_writable(text)?;
+    ensure!(!text.contains("=>"), "{:?} can't be written to a .zc library, it contains `=>`", text);
+    ensure!(text.trim() == text, "{:?} can't be written to a .zc library, surrounding whitespace is dropped", text);
+    Ok(())
+}
+
+// Keys match when they name the same strokes, however they are spelled
+fn same_keys(written: &str, keys: &str, wanted: Option<&[ChordKey]>) -> bool {
+    match (parse_strokes(written), wanted) {
+        (Some(written), Some(wanted)) => written == wanted,
+        _ => written.split_whitespace().eq(keys.split_whitespace()),
+    }
+}
+
+fn parse_strokes(keys: &str) -> Option<Vec<ChordKey>> {
+    keys.split_whitespace()
+        .map(|stroke| {
+            let keys = stroke.split('+').map(parse_key).collect::<Option<Vec<_>>>()?;
+            ChordKey::new(&keys)
+        })
+        .collect()
+}
+
+/// Render a library as a fresh document, see [`ChordLibrary::to_document`]
+pub(crate) fn from_library(library: &ChordLibrary) -> Result<Document> {
+    let mut out = String::new();
+    out.push_str(&format!("{} v{}\n", HEADER, FORMAT_VERSION));
+    for (key, value) in [
+        ("name", &library.meta.name),
+        ("language", &library.meta.language),
+        ("version", &library.meta.version),
+    ] {
+        if !value.is_empty() {
+            out.push_str(&format!("{}: {}\n", key, value));
+        }
+    }
+
+    for section in Section::ALL {
+        let mut entries: Vec<(String, &Expansion)> = library.section(section)
+            .iter()
+            .map(|(chord, expansion)| (chord.to_string(), expansion))
+            .collect();
+        if section == Section::Exception {
+            entries.extend(library.sequences.iter()
+                .map(|sequence| (join_strokes(&sequence.strokes), &sequence.expansion)));
+        }
+        if entries.is_empty() {
+            continue;
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        out.push_str(&format!("\n[{}]\n", section.name()));
+        for (keys, expansion) in entries {
+            expansion.check_writable().with_context(|| format!("Failed to write `{}`", keys))?;
+            out.push_str(&format!("{} => {}\n", keys, expansion.to_value()));
+        }
+    }
+
+    let mut shorthands: Vec<_> = library.shorthands.iter().collect();
+    if !shorthands.is_empty() {
+        shorthands.sort_by(|a, b| a.0.cmp(b.0));
+        out.push_str("\n[shorthands]\n");
+        for (abbreviation, expansion) in shorthands {
+            expansion.check_writable().with_context(|| format!("Failed to write `{}`", abbreviation))?;
+            out.push_str(&format!("{} => {}\n", abbreviation, expansion.to_value()));
+        }
+    }
+
+    Ok(Document::parse(&out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENGLISH: &str = include_str!("../../dictionaries/english.zc");
+
+    const SMALL: &str = concat!(
+        "ZIPCHORD LIBRARY v1\n",
+        "name: Small\n",
+        "\n",
+        "[chords]\n",
+        "  h+t  =>  the   # most common\n",
+        "a+n => an\n",
+        "\n",
+        "[suffixes]\n",
+        "i+n+g => ~ing\n",
+    );
+
+    #[test]
+    fn unedited_documents_are_written_back_unchanged() {
+        for content in [
+            ENGLISH.to_string(),
+            ENGLISH.replace('\n', "\r\n"),
+            ENGLISH.trim_end().to_string(),
+            SMALL.to_string(),
+        ] {
+            assert_eq!(Document::parse(&content).to_string(), content);
+        }
+    }
+
+    #[test]
+    fn set_keeps_spelling_spacing_and_comment() {
+        let mut document = Document::parse(SMALL);
+        document.set("chords", "KEY_T+KEY_H", "then").unwrap();
+
+        assert_eq!(document.get("chords", "t+h"), Some("then"));
+        assert_eq!(document.to_string(), SMALL.replace("=>  the   #", "=>  then   #"));
+    }
+
+    #[test]
+    fn set_adds_to_the_end_of_the_section() {
+        let mut document = Document::parse(SMALL);
+        document.set("chords", "o+f", "of").unwrap();
+        document.set("prefixes", "d+s", "dis~").unwrap();
+
+        let expected = SMALL
+            .replace("a+n => an\n", "a+n => an\no+f => of\n")
+            + "\n[prefixes]\nd+s => dis~\n";
+        assert_eq!(document.to_string(), expected);
+    }
+
+    #[test]
+    fn remove_takes_only_the_entry_line() {
+        let mut document = Document::parse(SMALL);
+
+        assert!(document.remove("chords", "n+a"));
+        assert!(!document.remove("chords", "n+a"));
+        assert_eq!(document.to_string(), SMALL.replace("a+n => an\n", ""));
+    }
+
+    #[test]
+    fn entry_keys_starting_like_metadata_stay_entries() {
+        let document = Document::parse("ZIPCHORD LIBRARY v1\nname: Small\n[chords]\nname: y => z\n");
+
+        assert_eq!(document.meta("name"), Some("Small"));
+        assert!(matches!(document.lines()[3].kind(), LineKind::Entry { .. }));
+    }
+
+    #[test]
+    fn set_meta_replaces_or_adds_known_keys() {
+        let mut document = Document::parse(SMALL);
+        document.set_meta("name", "Renamed").unwrap();
+        document.set_meta("language", "en").unwrap();
+
+        let expected = SMALL.replace("name: Small\n", "name: Renamed\nlanguage: en\n");
+        assert_eq!(document.to_string(), expected);
+        assert!(document.set_meta("author", "me").is_err());
+        assert!(document.set_meta("name", "a # b").is_err());
+        assert!(document.set_meta("name", "a => b").is_err());
+    }
+
+    #[test]
+    fn values_that_would_read_back_differently_are_rejected() {
+        let mut document = Document::parse(SMALL);
+
+        assert!(document.set("chords", "t+h", "a # b").is_err());
+        assert!(document.set("chords", "t+h", "line\nbreak").is_err());
+        assert!(document.set("chords", "t+h", "trailing ").is_err());
+        assert!(document.set("chords", "t+h => x", "the").is_err());
+        assert_eq!(document.to_string(), SMALL);
+    }
+
+    #[test]
+    fn libraries_are_written_only_when_they_read_back() {
+        let written = |text: &str, attach_left: bool, attach_right: bool| {
+            let expansion = Expansion { text: text.to_string(), attach_left, attach_right, source: None };
+            let mut library = ChordLibrary::new();
+            let chord = ChordKey::new(&[evdev::KeyCode::KEY_T, evdev::KeyCode::KEY_H]).unwrap();
+            library.insert(Section::Chord, &[chord], expansion.clone()).unwrap();
+            let document = library.to_document().ok()?;
+            let value = document.get("chords", "t+h")?;
+            assert_eq!(Expansion::parse(value), expansion, "{:?}", text);
+            Some(())
+        };
+
+        assert!(written("the", false, false).is_some());
+        assert!(written(". ", true, true).is_some());
+        assert!(written("~x", true, false).is_some());
+        assert!(written("~/home", false, false).is_none());
+        assert!(written("trailing ", false, false).is_none());
+        assert!(written(" leading", false, true).is_none());
+        assert!(written("x~", true, false).is_none());
+        assert!(written("", false, true).is_none());
+    }
+}
@@ -0,0 +1,120 @@
+use anyhow::{bail, Result};
+use std::{fmt, path::Path, sync::Arc};
+
+use super::document::check_writable;
+
+/// The output of a library entry with its `~` glue markers resolved.
+///
+/// A leading `~` attaches the output to the text before it (the space in
+/// front is deleted), a trailing `~` attaches it to whatever comes next
+/// (no automatic space is typed after it).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expansion {
+    pub text: String,
+    pub attach_left: bool,
+    pub attach_right: bool,
+    /// Where the entry was defined
+    pub source: Option<Source>,
+}
+
+/// File and 1-based line an entry comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Source {
+    pub file: Arc<Path>,
+    pub line: usize,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file.display(), self.line)
+    }
+}
+
+impl Expansion {
+    /// Parse a raw `.zc` value such as `dis~`, `~.com~` or `~. ~`
+    pub fn parse(value: &str) -> Self {
+        let (attach_left, rest) = match value.strip_prefix('~') {
+            Some(rest) => (true, rest),
+            None => (false, value),
+        };
+        let (attach_right, text) = match rest.strip_suffix('~') {
+            Some(text) => (true, text),
+            None => (false, rest),
+        };
+
+        Self {
+            text: text.to_string(),
+            attach_left,
+            attach_right,
+            source: None,
+        }
+    }
+
+    /// The value as written in a `.zc` file, the inverse of [`parse`](Self::parse).
+    ///
+    /// It only reads back as the same expansion when the text has no `#` or
+    /// line break, and a `~` or whitespace at either end of the text is
+    /// followed by glue on that side; otherwise the `~` would be read as
+    /// glue and the whitespace dropped. [`check_writable`](Self::check_writable)
+    /// tells whether that holds.
+    pub fn to_value(&self) -> String {
+        format!(
+            "{}{}{}",
+            if self.attach_left { "~" } else { "" },
+            self.text,
+            if self.attach_right { "~" } else { "" },
+        )
+    }
+
+    /// Check that [`to_value`](Self::to_value) reads back as this expansion
+    pub fn check_writable(&self) -> Result<()> {
+        check_writable(&self.text)?;
+        let loose = |c: char| c == '~' || c.is_whitespace();
+        if !self.attach_left && self.text.starts_with(loose) {
+            bail!("{:?} can't be written to a .zc library without glue before it", self.text);
+        }
+        // An empty text glued right only is a lone `~`, which reads as glue to the left
+        if (!self.attach_right && self.text.ends_with(loose)) || (self.text.is_empty() && self.attach_right && !self.attach_left) {
+            bail!("{:?} can't be written to a .zc library without glue after it", self.text);
+        }
+        Ok(())
+    }
+
+    /// Text to type, including the automatic trailing space unless glued right
+    pub fn output(&self) -> String {
+        if self.attach_right {
+            self.text.clone()
+        } else {
+            format!("{} ", self.text)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_resolves_glue_markers() {
+        let cases = [
+            ("the", "the", false, false),
+            ("dis~", "dis", false, true),
+            ("~ing", "ing", true, false),
+            ("~.com~", ".com", true, true),
+            ("~. ~", ". ", true, true),
+        ];
+        for (value, text, attach_left, attach_right) in cases {
+            let expansion = Expansion::parse(value);
+            assert_eq!((expansion.text.as_str(), expansion.attach_left, expansion.attach_right),
+                (text, attach_left, attach_right), "{}", value);
+            assert_eq!(expansion.to_value(), value);
+        }
+    }
+
+    #[test]
+    fn output_adds_a_space_unless_glued_right() {
+        assert_eq!(Expansion::parse("the").output(), "the ");
+        assert_eq!(Expansion::parse("~ing").output(), "ing ");
+        assert_eq!(Expansion::parse("dis~").output(), "dis");
+    }
+}
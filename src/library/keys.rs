@@ -0,0 +1,56 @@
+use evdev::KeyCode;
+use std::str::FromStr;
+
+/// Friendly names accepted in `.zc` files in addition to evdev's own `KEY_*` names
+const ALIASES: &[(&str, KeyCode)] = &[
+    ("PERIOD", KeyCode::KEY_DOT),
+    ("FULLSTOP", KeyCode::KEY_DOT),
+    (".", KeyCode::KEY_DOT),
+    (",", KeyCode::KEY_COMMA),
+    (";", KeyCode::KEY_SEMICOLON),
+    ("QUOTE", KeyCode::KEY_APOSTROPHE),
+    ("'", KeyCode::KEY_APOSTROPHE),
+    ("BACKTICK", KeyCode::KEY_GRAVE),
+    ("`", KeyCode::KEY_GRAVE),
+    ("/", KeyCode::KEY_SLASH),
+    ("\\", KeyCode::KEY_BACKSLASH),
+    ("DASH", KeyCode::KEY_MINUS),
+    ("HYPHEN", KeyCode::KEY_MINUS),
+    ("-", KeyCode::KEY_MINUS),
+    ("EQUALS", KeyCode::KEY_EQUAL),
+    ("=", KeyCode::KEY_EQUAL),
+    ("LEFTBRACKET", KeyCode::KEY_LEFTBRACE),
+    ("[", KeyCode::KEY_LEFTBRACE),
+    ("RIGHTBRACKET", KeyCode::KEY_RIGHTBRACE),
+    ("]", KeyCode::KEY_RIGHTBRACE),
+    ("SPC", KeyCode::KEY_SPACE),
+    ("RETURN", KeyCode::KEY_ENTER),
+    ("BKSP", KeyCode::KEY_BACKSPACE),
+    ("ESC", KeyCode::KEY_ESC),
+    ("SHIFT", KeyCode::KEY_LEFTSHIFT),
+    ("CTRL", KeyCode::KEY_LEFTCTRL),
+    ("ALT", KeyCode::KEY_LEFTALT),
+];
+
+/// Parse a key name from a `.zc` file into a [`KeyCode`].
+///
+/// Accepts evdev names (`KEY_DOT`), the same without the prefix (`DOT`),
+/// single characters (`e`, `.`) and the aliases above, case-insensitively.
+pub fn parse_key(name: &str) -> Option<KeyCode> {
+    let upper = name.trim().to_ascii_uppercase();
+    let base = upper.strip_prefix("KEY_").unwrap_or(&upper);
+    if base.is_empty() {
+        return None;
+    }
+
+    ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == base)
+        .map(|(_, key)| *key)
+        .or_else(|| KeyCode::from_str(&format!("KEY_{}", base)).ok())
+}
+
+/// Whether evdev knows the key, so it can appear in a chord
+pub(crate) fn is_known_key(key: KeyCode) -> bool {
+    key != KeyCode::KEY_RESERVED && KeyCode::from_str(&format!("{:?}", key)).is_ok_and(|parsed| parsed == key)
+}
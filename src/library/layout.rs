@@ -0,0 +1,381 @@
+use anyhow::{bail, Context, Result};
+use evdev::KeyCode;
+use std::{collections::HashMap, fmt, fs, path::Path, str::FromStr};
+
+use super::keys::parse_key;
+
+/// Physical keys of the four character rows, left to right
+const ROWS: [&[KeyCode]; 4] = [
+    &[
+        KeyCode::KEY_GRAVE, KeyCode::KEY_1, KeyCode::KEY_2, KeyCode::KEY_3, KeyCode::KEY_4,
+        KeyCode::KEY_5, KeyCode::KEY_6, KeyCode::KEY_7, KeyCode::KEY_8, KeyCode::KEY_9,
+        KeyCode::KEY_0, KeyCode::KEY_MINUS, KeyCode::KEY_EQUAL,
+    ],
+    &[
+        KeyCode::KEY_Q, KeyCode::KEY_W, KeyCode::KEY_E, KeyCode::KEY_R, KeyCode::KEY_T,
+        KeyCode::KEY_Y, KeyCode::KEY_U, KeyCode::KEY_I, KeyCode::KEY_O, KeyCode::KEY_P,
+        KeyCode::KEY_LEFTBRACE, KeyCode::KEY_RIGHTBRACE, KeyCode::KEY_BACKSLASH,
+    ],
+    &[
+        KeyCode::KEY_A, KeyCode::KEY_S, KeyCode::KEY_D, KeyCode::KEY_F, KeyCode::KEY_G,
+        KeyCode::KEY_H, KeyCode::KEY_J, KeyCode::KEY_K, KeyCode::KEY_L, KeyCode::KEY_SEMICOLON,
+        KeyCode::KEY_APOSTROPHE,
+    ],
+    &[
+        KeyCode::KEY_Z, KeyCode::KEY_X, KeyCode::KEY_C, KeyCode::KEY_V, KeyCode::KEY_B,
+        KeyCode::KEY_N, KeyCode::KEY_M, KeyCode::KEY_COMMA, KeyCode::KEY_DOT, KeyCode::KEY_SLASH,
+    ],
+];
+
+/// Characters typed by each row of [`ROWS`], unshifted and shifted
+type Legends = [(&'static str, &'static str); 4];
+
+const QWERTY: Legends = [
+    ("`1234567890-=", "~!@#$%^&*()_+"),
+    ("qwertyuiop[]\\", "QWERTYUIOP{}|"),
+    ("asdfghjkl;'", "ASDFGHJKL:\""),
+    ("zxcvbnm,./", "ZXCVBNM<>?"),
+];
+
+const COLEMAK: Legends = [
+    ("`1234567890-=", "~!@#$%^&*()_+"),
+    ("qwfpgjluy;[]\\", "QWFPGJLUY:{}|"),
+    ("arstdhneio'", "ARSTDHNEIO\""),
+    ("zxcvbkm,./", "ZXCVBKM<>?"),
+];
+
+const DVORAK: Legends = [
+    ("`1234567890[]", "~!@#$%^&*(){}"),
+    ("',.pyfgcrl/=\\", "\"<>PYFGCRL?+|"),
+    ("aoeuidhtns-", "AOEUIDHTNS_"),
+    (";qjkxbmwvz", ":QJKXBMWVZ"),
+];
+
+/// XKB names of the keys in [`ROWS`], for symbol files without keycodes
+const XKB_ROWS: [&[&str]; 4] = [
+    &["TLDE", "AE01", "AE02", "AE03", "AE04", "AE05", "AE06", "AE07", "AE08", "AE09", "AE10", "AE11", "AE12"],
+    &["AD01", "AD02", "AD03", "AD04", "AD05", "AD06", "AD07", "AD08", "AD09", "AD10", "AD11", "AD12", "BKSL"],
+    &["AC01", "AC02", "AC03", "AC04", "AC05", "AC06", "AC07", "AC08", "AC09", "AC10", "AC11"],
+    &["AB01", "AB02", "AB03", "AB04", "AB05", "AB06", "AB07", "AB08", "AB09", "AB10"],
+];
+
+/// XKB keysym names of the printable ASCII characters that aren't their own name
+const KEYSYMS: [(&str, char); 33] = [
+    ("space", ' '), ("exclam", '!'), ("quotedbl", '"'), ("numbersign", '#'), ("dollar", '$'),
+    ("percent", '%'), ("ampersand", '&'), ("apostrophe", '\''), ("parenleft", '('),
+    ("parenright", ')'), ("asterisk", '*'), ("plus", '+'), ("comma", ','), ("minus", '-'),
+    ("period", '.'), ("slash", '/'), ("colon", ':'), ("semicolon", ';'), ("less", '<'),
+    ("equal", '='), ("greater", '>'), ("question", '?'), ("at", '@'), ("bracketleft", '['),
+    ("backslash", '\\'), ("bracketright", ']'), ("asciicircum", '^'), ("underscore", '_'),
+    ("grave", '`'), ("braceleft", '{'), ("bar", '|'), ("braceright", '}'), ("asciitilde", '~'),
+];
+
+const BUILTIN: [(&str, Legends); 3] = [
+    ("qwerty", QWERTY),
+    ("colemak", COLEMAK),
+    ("dvorak", DVORAK),
+];
+
+/// The physical key that types a character, and whether Shift is held for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Keystroke {
+    pub key: KeyCode,
+    pub shift: bool,
+}
+
+/// Which physical key types each character on a keyboard layout
+#[derive(Clone, PartialEq, Eq)]
+pub struct Layout {
+    name: String,
+    keys: HashMap<char, Keystroke>,
+    // The inverse of `keys`, for the character a key types
+    chars: HashMap<Keystroke, char>,
+}
+
+impl Layout {
+    fn new(name: String, keys: HashMap<char, Keystroke>) -> Self {
+        // Where several characters share a key the lowest wins, so the
+        // result doesn't depend on the map's iteration order
+        let mut chars = HashMap::with_capacity(keys.len());
+        for (&c, &keystroke) in &keys {
+            chars.entry(keystroke)
+                .and_modify(|typed: &mut char| *typed = (*typed).min(c))
+                .or_insert(c);
+        }
+        Self { name, keys, chars }
+    }
+
+    /// A built-in layout: `qwerty`, `colemak` or `dvorak`
+    pub fn builtin(name: &str) -> Option<Self> {
+        let (name, legends) = BUILTIN.iter()
+            .find(|(builtin, _)| builtin.eq_ignore_ascii_case(name.trim()))?;
+
+        let mut keys = HashMap::from([(' ', Keystroke { key: KeyCode::KEY_SPACE, shift: false })]);
+        for (row, (plain, shifted)) in ROWS.iter().zip(legends) {
+            for (&key, c) in row.iter().zip(plain.chars()) {
+                keys.insert(c, Keystroke { key, shift: false });
+            }
+            for (&key, c) in row.iter().zip(shifted.chars()) {
+                keys.insert(c, Keystroke { key, shift: true });
+            }
+        }
+
+        Some(Self::new(name.to_string(), keys))
+    }
+
+    /// Read the layout from an XKB keymap, such as the output of
+    /// `xkbcomp $DISPLAY keymap.xkb`, or a single-layout symbols file.
+    ///
+    /// The first two levels of each key are used, unshifted and shifted.
+    /// Keycodes come from the keymap's `xkb_keycodes` section when it has
+    /// one, and from the standard evdev key names otherwise. Only the first
+    /// `xkb_symbols` block is read, and its `include` lines aren't followed.
+    pub fn from_xkb(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+
+        let mut codes: HashMap<&str, KeyCode> = XKB_ROWS.iter().zip(ROWS)
+            .flat_map(|(names, keys)| names.iter().copied().zip(keys.iter().copied()))
+            .chain([("SPCE", KeyCode::KEY_SPACE), ("LSGT", KeyCode::KEY_102ND)])
+            .collect();
+        // `<AC01> = 38;`, XKB keycodes are evdev codes offset by 8
+        for line in content.lines() {
+            let Some((name, code)) = line.trim().strip_suffix(';').and_then(|line| line.split_once('=')) else {
+                continue;
+            };
+            let name = name.trim();
+            if let (Some(name), Ok(code)) = (
+                name.strip_prefix('<').and_then(|name| name.strip_suffix('>')),
+                code.trim().parse::<u16>(),
+            ) {
+                codes.insert(name, KeyCode::new(code.saturating_sub(8)));
+            }
+        }
+
+        const SYMBOLS: &str = "xkb_symbols";
+        let Some(start) = content.find(SYMBOLS) else {
+            bail!("No `{}` block found in {}", SYMBOLS, path.display());
+        };
+        // Up to the next block, if there is one
+        let symbols = &content[start..];
+        let symbols = match symbols[SYMBOLS.len()..].find(SYMBOLS) {
+            Some(end) => &symbols[..SYMBOLS.len() + end],
+            None => symbols,
+        };
+
+        let mut keys = HashMap::new();
+        for line in symbols.lines() {
+            // `key <AC01> { [ a, A ] };` with optional `type=` and `symbols[Group1]=`
+            let Some(rest) = line.trim().strip_prefix("key") else {
+                continue;
+            };
+            let Some(name) = rest.trim().strip_prefix('<').and_then(|rest| rest.split_once('>')).map(|(name, _)| name) else {
+                continue;
+            };
+            // The first `[...]` that isn't a `[Group1]` index
+            let levels = rest.split('[').skip(1)
+                .filter_map(|part| part.split_once(']').map(|(levels, _)| levels))
+                .find(|levels| !levels.trim_start().starts_with("Group"));
+            let (Some(&key), Some(levels)) = (codes.get(name), levels) else {
+                continue;
+            };
+
+            for (level, keysym) in levels.split(',').take(2).enumerate() {
+                if let Some(c) = keysym_char(keysym.trim()) {
+                    keys.entry(c).or_insert(Keystroke { key, shift: level == 1 });
+                }
+            }
+        }
+        if keys.is_empty() {
+            bail!("No key symbols found in {}", path.display());
+        }
+
+        let name = path.file_stem().map_or_else(|| path.display().to_string(), |stem| stem.to_string_lossy().into_owned());
+        Ok(Self::new(name, keys))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The key typing `c`, if the layout has one
+    pub fn keystroke(&self, c: char) -> Option<Keystroke> {
+        self.keys.get(&c).copied()
+    }
+
+    /// Resolve a key in a `.zc` chord: a single character is looked up in
+    /// the layout, letters case-insensitively, and anything longer is a key
+    /// name for [`parse_key`]
+    pub fn resolve_key(&self, name: &str) -> Result<KeyCode, String> {
+        let mut chars = name.chars();
+        let (Some(c), None) = (chars.next(), chars.next()) else {
+            return parse_key(name).ok_or_else(|| format!("Unknown key name `{}`", name));
+        };
+
+        let keystroke = self.keystroke(c)
+            .filter(|keystroke| !keystroke.shift)
+            .or_else(|| c.to_lowercase().next().and_then(|lower| self.keystroke(lower)));
+        match keystroke {
+            Some(keystroke) if !keystroke.shift => Ok(keystroke.key),
+            Some(_) => Err(format!("`{}` needs Shift on the {} layout, which a chord can't hold", c, self.name)),
+            None => Err(format!("No key types `{}` on the {} layout", c, self.name)),
+        }
+    }
+
+    // Every character and its key, sorted, for fingerprinting the layout
+    pub(crate) fn sorted_keys(&self) -> Vec<(char, Keystroke)> {
+        let mut keys: Vec<_> = self.keys.iter().map(|(c, keystroke)| (*c, *keystroke)).collect();
+        keys.sort_by_key(|(c, _)| *c);
+        keys
+    }
+
+    /// The character typed by a key, the inverse of [`keystroke`](Self::keystroke)
+    pub fn character(&self, keystroke: Keystroke) -> Option<char> {
+        self.chars.get(&keystroke).copied()
+    }
+}
+
+// The character of a keysym such as `a`, `comma` or `U00E9`
+fn keysym_char(keysym: &str) -> Option<char> {
+    let mut chars = keysym.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(c);
+    }
+    if let Some(code) = keysym.strip_prefix('U') {
+        return u32::from_str_radix(code, 16).ok().and_then(char::from_u32);
+    }
+    KEYSYMS.iter().find(|(name, _)| *name == keysym).map(|(_, c)| *c)
+}
+
+impl fmt::Debug for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Layout").field(&self.name).finish()
+    }
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self::builtin("qwerty").expect("qwerty is built in")
+    }
+}
+
+impl FromStr for Layout {
+    type Err = anyhow::Error;
+
+    /// A built-in layout name, or the path of an XKB keymap
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(layout) = Self::builtin(s) {
+            return Ok(layout);
+        }
+        let path = Path::new(s.trim());
+        if !path.is_file() {
+            bail!("Unknown keyboard layout `{}` (expected qwerty, colemak, dvorak or an XKB keymap file)", s.trim());
+        }
+        Self::from_xkb(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plain(key: KeyCode) -> Keystroke {
+        Keystroke { key, shift: false }
+    }
+
+    fn shifted(key: KeyCode) -> Keystroke {
+        Keystroke { key, shift: true }
+    }
+
+    #[test]
+    fn builtin_layouts_map_both_ways() {
+        let qwerty = Layout::builtin("qwerty").unwrap();
+        let colemak = Layout::builtin(" Colemak ").unwrap();
+        let dvorak = Layout::builtin("DVORAK").unwrap();
+        assert!(Layout::builtin("azerty").is_none());
+        assert_eq!(colemak.name(), "colemak");
+
+        assert_eq!(qwerty.keystroke('a'), Some(plain(KeyCode::KEY_A)));
+        assert_eq!(qwerty.keystroke('?'), Some(shifted(KeyCode::KEY_SLASH)));
+        assert_eq!(colemak.keystroke('r'), Some(plain(KeyCode::KEY_S)));
+        assert_eq!(dvorak.keystroke('o'), Some(plain(KeyCode::KEY_S)));
+        assert_eq!(dvorak.keystroke('"'), Some(shifted(KeyCode::KEY_Q)));
+        assert_eq!(qwerty.keystroke('é'), None);
+
+        for layout in [&qwerty, &colemak, &dvorak] {
+            // Every row, both levels, and space
+            assert_eq!(layout.sorted_keys().len(), 95, "{:?}", layout);
+            for (c, keystroke) in layout.sorted_keys() {
+                assert_eq!(layout.character(keystroke), Some(c), "{:?}", layout);
+            }
+        }
+        assert_eq!(dvorak.character(plain(KeyCode::KEY_SPACE)), Some(' '));
+        assert_eq!(qwerty.character(plain(KeyCode::KEY_F13)), None);
+    }
+
+    #[test]
+    fn single_characters_resolve_through_the_layout() {
+        let qwerty = Layout::default();
+        let colemak = Layout::builtin("colemak").unwrap();
+
+        // `t` follows the layout, `KEY_T` is always the same physical key
+        assert_eq!(qwerty.resolve_key("t"), Ok(KeyCode::KEY_T));
+        assert_eq!(colemak.resolve_key("t"), Ok(KeyCode::KEY_F));
+        assert_eq!(colemak.resolve_key("KEY_T"), Ok(KeyCode::KEY_T));
+        assert_eq!(colemak.resolve_key("h"), Ok(KeyCode::KEY_H));
+        assert_eq!(colemak.resolve_key("T"), Ok(KeyCode::KEY_F));
+        assert_eq!(colemak.resolve_key("space"), Ok(KeyCode::KEY_SPACE));
+
+        assert!(qwerty.resolve_key("?").unwrap_err().contains("needs Shift"));
+        assert!(qwerty.resolve_key("é").unwrap_err().contains("No key types"));
+        assert!(qwerty.resolve_key("NOPE").unwrap_err().contains("Unknown key name"));
+    }
+
+    #[test]
+    fn xkb_keymaps_are_read() {
+        let dir = std::env::temp_dir().join(format!("zipchord-layout-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("custom.xkb");
+        fs::write(&path, concat!(
+            "xkb_keymap {\n",
+            "xkb_keycodes \"test\" {\n",
+            "    <AC01> = 39;\n",
+            "};\n",
+            "xkb_symbols \"test\" {\n",
+            "    key <AC01> { [ a, A ] };\n",
+            "    key <AC02> { type= \"TWO_LEVEL\", symbols[Group1]= [ U00E9, U00C9 ] };\n",
+            "    key <AB08> { [ comma, less, dead_cedilla ] };\n",
+            "    key <SPCE> { [ space ] };\n",
+            "    key <NOPE> { [ x, X ] };\n",
+            "};\n",
+            "xkb_symbols \"second\" {\n",
+            "    key <AC03> { [ z, Z ] };\n",
+            "};\n",
+            "};\n",
+        )).unwrap();
+
+        let layout = Layout::from_xkb(&path).unwrap();
+        assert_eq!(layout.name(), "custom");
+        // Keycodes from the keymap are offset by 8, the rest are the standard ones
+        assert_eq!(layout.keystroke('a'), Some(plain(KeyCode::KEY_S)));
+        assert_eq!(layout.keystroke('A'), Some(shifted(KeyCode::KEY_S)));
+        assert_eq!(layout.keystroke('é'), Some(plain(KeyCode::KEY_S)));
+        assert_eq!(layout.keystroke('É'), Some(shifted(KeyCode::KEY_S)));
+        assert_eq!(layout.keystroke('<'), Some(shifted(KeyCode::KEY_COMMA)));
+        assert_eq!(layout.keystroke(' '), Some(plain(KeyCode::KEY_SPACE)));
+        assert_eq!(layout.keystroke('x'), None);
+        assert_eq!(layout.keystroke('z'), None);
+        // Both `a` and `é` are on KEY_S, the lower one is typed
+        assert_eq!(layout.character(plain(KeyCode::KEY_S)), Some('a'));
+        assert_eq!(layout.character(shifted(KeyCode::KEY_S)), Some('A'));
+        assert_eq!(path.to_str().unwrap().parse::<Layout>().unwrap(), layout);
+
+        fs::write(&path, "xkb_keycodes \"test\" {\n};\n").unwrap();
+        assert!(Layout::from_xkb(&path).unwrap_err().to_string().contains("No `xkb_symbols` block"));
+        fs::write(&path, "xkb_symbols \"test\" {\n};\n").unwrap();
+        assert!(Layout::from_xkb(&path).unwrap_err().to_string().contains("No key symbols"));
+        assert!("azerty".parse::<Layout>().unwrap_err().to_string().contains("Unknown keyboard layout"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
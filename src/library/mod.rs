@@ -0,0 +1,531 @@
+use anyhow::{bail, ensure, Context, Result};
+use evdev::KeyCode;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+};
+
+mod builder;
+pub mod cache;
+mod chord;
+mod diagnostics;
+mod document;
+mod expansion;
+mod keys;
+mod layout;
+mod parser;
+mod reverse;
+mod sequence;
+mod shorthand;
+
+pub use builder::LibraryBuilder;
+pub use chord::{ChordKey, MAX_CHORD_KEYS};
+pub use diagnostics::{Diagnostic, Diagnostics, Severity};
+pub use document::{Document, Line, LineKind, Span};
+pub use expansion::{Expansion, Source};
+pub use keys::parse_key;
+pub use layout::{Keystroke, Layout};
+pub use reverse::Origin;
+pub use sequence::{Sequence, SequenceMatch, SequenceMatcher, DEFAULT_SEQUENCE_TIMEOUT};
+pub use shorthand::{ShorthandBuffer, ShorthandMatch, SHORTHAND_DELIMITERS};
+
+use chord::join_strokes;
+pub(crate) use keys::is_known_key;
+use parser::LibraryParser;
+use reverse::ReverseIndex;
+use shorthand::check_abbreviation;
+
+/// Chords and sequences mapped to the text they type.
+///
+/// Libraries are loaded from `.zc` files, or built in code with
+/// [`LibraryBuilder`] and [`insert`](Self::insert), which keep the same
+/// invariants as the parser.
+#[derive(Debug, Clone, Default)]
+pub struct ChordLibrary {
+    meta: LibraryMeta,
+    chords: HashMap<ChordKey, Expansion>,
+    prefixes: HashMap<ChordKey, Expansion>,
+    suffixes: HashMap<ChordKey, Expansion>,
+    infixes: HashMap<ChordKey, Expansion>,
+    exceptions: HashMap<ChordKey, Expansion>,
+    // Multi-stroke `[exceptions]` entries, see `SequenceMatcher`
+    sequences: Vec<Sequence>,
+    // `[shorthands]` keyed by abbreviation, see `ShorthandBuffer`
+    shorthands: HashMap<String, Expansion>,
+    // Built by the first `lookup`, so loading for typing never pays for it
+    reverse: OnceLock<ReverseIndex>,
+}
+
+/// Newest `ZIPCHORD LIBRARY vN` format this crate reads
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Default)]
+pub struct LibraryMeta {
+    pub name: String,
+    pub language: String,
+    pub version: String,
+    /// Version from the `ZIPCHORD LIBRARY vN` header
+    pub format_version: u32,
+}
+
+/// Which definition survives when a chord or sequence is defined twice
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    FirstWins,
+    #[default]
+    LastWins,
+    /// Report every duplicate as an error and fail the load
+    Error,
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "first" | "first-wins" => Ok(Self::FirstWins),
+            "last" | "last-wins" => Ok(Self::LastWins),
+            "error" => Ok(Self::Error),
+            other => bail!("Unknown duplicate policy `{}` (expected first, last or error)", other),
+        }
+    }
+}
+
+/// How a library file is loaded
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    /// Fail the load when any error diagnostic is reported
+    pub strict: bool,
+    pub duplicates: DuplicatePolicy,
+    /// Read the compiled `.zcc` cache next to the file when it is current,
+    /// and refresh it after parsing otherwise
+    pub cache: bool,
+    /// Keyboard layout that chords written by character, such as `t+h`,
+    /// are resolved through
+    pub layout: Layout,
+}
+
+impl ChordLibrary {
+    /// An empty library
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn builder() -> LibraryBuilder {
+        LibraryBuilder::new()
+    }
+
+    /// Load a library, returning it together with everything the parser reported
+    pub fn load(path: &Path) -> Result<(Self, Diagnostics)> {
+        Self::load_with(path, &LoadOptions::default())
+    }
+
+    /// Load a library, failing with its [`Diagnostics`] if it has errors
+    pub fn load_strict(path: &Path) -> Result<Self> {
+        let options = LoadOptions { strict: true, ..Default::default() };
+        Self::load_with(path, &options).map(|(library, _)| library)
+    }
+
+    pub fn load_with(path: &Path, options: &LoadOptions) -> Result<(Self, Diagnostics)> {
+        let cached = if options.cache { cache::read(path, options) } else { None };
+        let (library, diagnostics) = match cached {
+            Some(loaded) => loaded,
+            None => {
+                let (library, diagnostics, sources) = Self::parse_file(path, options)?;
+                if options.cache {
+                    if let Err(e) = cache::write(path, options, &library, &diagnostics, &sources) {
+                        log::warn!("Failed to cache {}: {:#}", path.display(), e);
+                    }
+                }
+                (library, diagnostics)
+            }
+        };
+
+        if options.strict && diagnostics.has_errors() {
+            return Err(anyhow::Error::new(diagnostics)
+                .context(format!("Failed to load {}", path.display())));
+        }
+
+        Ok((library, diagnostics))
+    }
+
+    /// Parse a library and write its `.zcc` cache regardless of whether the
+    /// current one is up to date. Returns the cache path.
+    pub fn compile(path: &Path, options: &LoadOptions) -> Result<(PathBuf, Diagnostics)> {
+        let (library, diagnostics, sources) = Self::parse_file(path, options)?;
+        let cache = cache::write(path, options, &library, &diagnostics, &sources)?;
+        Ok((cache, diagnostics))
+    }
+
+    // Parse without the cache, also returning every file read and every
+    // include target that was missing
+    fn parse_file(path: &Path, options: &LoadOptions) -> Result<(Self, Diagnostics, Vec<PathBuf>)> {
+        let mut sources = Vec::new();
+        let (library, diagnostics) = Self::load_included(path, options, &mut Vec::new(), &mut sources)?;
+        Ok((library, diagnostics, sources))
+    }
+
+    /// Load a file and everything it includes; `stack` holds the files
+    /// currently being loaded, to detect include cycles
+    fn load_included(
+        path: &Path,
+        options: &LoadOptions,
+        stack: &mut Vec<PathBuf>,
+        sources: &mut Vec<PathBuf>,
+    ) -> Result<(Self, Diagnostics)> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        sources.push(path.to_path_buf());
+
+        let mut parser = LibraryParser::new(path.into(), options.duplicates, &options.layout);
+        parser.parse(&content)
+            .with_context(|| format!("Failed to load {}", path.display()))?;
+        let (mut library, mut diagnostics, includes) = parser.into_library();
+
+        stack.push(fs::canonicalize(path)?);
+        let mut included = Vec::with_capacity(includes.len());
+        for include in includes {
+            // Relative includes are resolved against the including file
+            let target = path.parent().unwrap_or(Path::new("")).join(&include.path);
+            let error = |message: String| Diagnostic {
+                file: path.to_path_buf(),
+                line: include.line,
+                column: include.column,
+                severity: Severity::Error,
+                message,
+            };
+
+            let canonical = match fs::canonicalize(&target) {
+                Ok(canonical) => canonical,
+                Err(e) => {
+                    diagnostics.push(error(format!("Cannot include {}: {}", target.display(), e)));
+                    // The cache is stale once the target appears
+                    sources.push(target);
+                    continue;
+                }
+            };
+            if let Some(start) = stack.iter().position(|file| *file == canonical) {
+                let cycle: Vec<String> = stack[start..].iter()
+                    .chain([&canonical])
+                    .map(|file| file.display().to_string())
+                    .collect();
+                diagnostics.push(error(format!("Include cycle: {}", cycle.join(" -> "))));
+                continue;
+            }
+
+            let (library, reported) = Self::load_included(&target, options, stack, sources)?;
+            diagnostics.extend(reported);
+            included.push(library);
+        }
+        stack.pop();
+
+        // The including file overrides its includes, later includes override earlier ones
+        for lower in included.into_iter().rev() {
+            library.merge(lower);
+        }
+
+        Ok((library, diagnostics))
+    }
+
+    /// Load several libraries as layers, the first taking priority.
+    ///
+    /// An entry from a lower layer is only used when no higher layer defines
+    /// the same chord or sequence; [`Expansion::source`] tells them apart.
+    pub fn load_layered(paths: &[PathBuf], options: &LoadOptions) -> Result<(Self, Diagnostics)> {
+        let mut layered: Option<Self> = None;
+        let mut diagnostics = Diagnostics::default();
+
+        for path in paths {
+            let (library, reported) = Self::load_with(path, options)?;
+            diagnostics.extend(reported);
+            match layered.as_mut() {
+                Some(higher) => higher.merge(library),
+                None => layered = Some(library),
+            }
+        }
+
+        Ok((layered.unwrap_or_default(), diagnostics))
+    }
+
+    /// Write the library as a fresh `.zc` document, entries sorted by keys.
+    ///
+    /// Glue is always written as explicit `~` markers. Fails on an expansion
+    /// that wouldn't read back the same, see [`Expansion::to_value`]. To
+    /// edit an existing file without losing its layout, use [`Document`].
+    pub fn to_document(&self) -> Result<Document> {
+        document::from_library(self)
+    }
+
+    /// Add the entries of a lower-priority library that this one doesn't define
+    pub fn merge(&mut self, lower: ChordLibrary) {
+        let sections = [
+            (lower.chords, Section::Chord),
+            (lower.prefixes, Section::Prefix),
+            (lower.suffixes, Section::Suffix),
+            (lower.infixes, Section::Infix),
+            (lower.exceptions, Section::Exception),
+        ];
+        for (entries, section) in sections {
+            for (chord, expansion) in entries {
+                if self.defines(&chord) {
+                    log::debug!("{} from {:?} is shadowed by a higher layer", chord, expansion.source);
+                    continue;
+                }
+                self.section_mut(section).insert(chord, expansion);
+            }
+        }
+
+        for sequence in lower.sequences {
+            if self.sequences.iter().any(|s| s.strokes == sequence.strokes) {
+                log::debug!("{} from {:?} is shadowed by a higher layer",
+                    join_strokes(&sequence.strokes), sequence.expansion.source);
+                continue;
+            }
+            self.sequences.push(sequence);
+        }
+
+        for (abbreviation, expansion) in lower.shorthands {
+            if self.shorthands.contains_key(&abbreviation) {
+                log::debug!("Shorthand {} from {:?} is shadowed by a higher layer", abbreviation, expansion.source);
+                continue;
+            }
+            self.shorthands.insert(abbreviation, expansion);
+        }
+
+        self.reindex();
+    }
+
+    /// Every entry typing exactly `text`, without glue markers, shortest first
+    pub fn lookup(&self, text: &str) -> &[Origin] {
+        self.reverse.get_or_init(|| ReverseIndex::build(self)).get(text)
+    }
+
+    // Drop the reverse index after a bulk change, the next lookup rebuilds it
+    fn reindex(&mut self) {
+        self.reverse = OnceLock::new();
+    }
+
+    pub fn meta(&self) -> &LibraryMeta {
+        &self.meta
+    }
+
+    pub fn meta_mut(&mut self) -> &mut LibraryMeta {
+        &mut self.meta
+    }
+
+    /// Multi-stroke `[exceptions]` entries, see [`SequenceMatcher`]
+    pub fn sequences(&self) -> &[Sequence] {
+        &self.sequences
+    }
+
+    /// `[shorthands]` entries keyed by abbreviation, see [`ShorthandBuffer`]
+    pub fn shorthands(&self) -> &HashMap<String, Expansion> {
+        &self.shorthands
+    }
+
+    /// Number of entries, sequences and shorthands included
+    pub fn len(&self) -> usize {
+        [&self.chords, &self.prefixes, &self.suffixes, &self.infixes, &self.exceptions]
+            .iter()
+            .map(|entries| entries.len())
+            .sum::<usize>()
+            + self.sequences.len()
+            + self.shorthands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The entry for a chord, or for the strokes of a sequence
+    pub fn get(&self, strokes: &[ChordKey]) -> Option<(Section, &Expansion)> {
+        if let [chord] = strokes {
+            return Section::ALL.into_iter()
+                .find_map(|section| self.section(section).get(chord).map(|expansion| (section, expansion)));
+        }
+        self.sequences.iter()
+            .find(|sequence| sequence.strokes == strokes)
+            .map(|sequence| (Section::Exception, &sequence.expansion))
+    }
+
+    /// Add an entry, replacing and returning the one defined for the same
+    /// strokes in any section.
+    ///
+    /// As in `.zc` files, only `[exceptions]` entries may have several
+    /// strokes, affix sections force their glue onto the expansion, and
+    /// every key must be a known evdev key.
+    pub fn insert(
+        &mut self,
+        section: Section,
+        strokes: &[ChordKey],
+        mut expansion: Expansion,
+    ) -> Result<Option<(Section, Expansion)>> {
+        ensure!(!strokes.is_empty(), "An entry needs at least one chord");
+        ensure!(strokes.len() == 1 || section == Section::Exception,
+            "`{}` has several strokes, which only [exceptions] entries can have", join_strokes(strokes));
+        for stroke in strokes {
+            ensure!(!stroke.is_empty(), "A chord needs at least one key");
+            if let Some(key) = stroke.keys().find(|key| !is_known_key(*key)) {
+                bail!("Unknown key code {}", key.code());
+            }
+        }
+        section.glue(&mut expansion);
+
+        let previous = self.remove(strokes);
+        if let Some(reverse) = self.reverse.get_mut() {
+            reverse.insert(&expansion.text, Origin::Chord {
+                strokes: strokes.to_vec(),
+                section,
+                source: expansion.source.clone(),
+            });
+        }
+        match strokes {
+            [chord] => { self.section_mut(section).insert(*chord, expansion); }
+            _ => self.sequences.push(Sequence { strokes: strokes.to_vec(), expansion }),
+        }
+        Ok(previous)
+    }
+
+    /// Add a shorthand, replacing and returning the one defined for the same
+    /// abbreviation. The abbreviation must be a single word, and the
+    /// expansion can't glue to the text before it.
+    pub fn insert_shorthand(&mut self, abbreviation: &str, expansion: Expansion) -> Result<Option<Expansion>> {
+        check_abbreviation(abbreviation)?;
+        ensure!(!expansion.attach_left, "Shorthand `{}` can't glue to the text before it", abbreviation);
+
+        let previous = self.remove_shorthand(abbreviation);
+        if let Some(reverse) = self.reverse.get_mut() {
+            reverse.insert(&expansion.text, Origin::Shorthand {
+                abbreviation: abbreviation.to_string(),
+                source: expansion.source.clone(),
+            });
+        }
+        self.shorthands.insert(abbreviation.to_string(), expansion);
+        Ok(previous)
+    }
+
+    pub fn remove_shorthand(&mut self, abbreviation: &str) -> Option<Expansion> {
+        let expansion = self.shorthands.remove(abbreviation)?;
+        if let Some(reverse) = self.reverse.get_mut() {
+            reverse.remove_shorthand(&expansion.text, abbreviation);
+        }
+        Some(expansion)
+    }
+
+    /// Remove the entry for a chord, or for the strokes of a sequence
+    pub fn remove(&mut self, strokes: &[ChordKey]) -> Option<(Section, Expansion)> {
+        let (section, expansion) = match strokes {
+            [chord] => Section::ALL.into_iter()
+                .find_map(|section| self.section_mut(section).remove(chord).map(|expansion| (section, expansion)))?,
+            _ => {
+                let index = self.sequences.iter().position(|sequence| sequence.strokes == strokes)?;
+                (Section::Exception, self.sequences.remove(index).expansion)
+            }
+        };
+        if let Some(reverse) = self.reverse.get_mut() {
+            reverse.remove(&expansion.text, strokes);
+        }
+        Some((section, expansion))
+    }
+
+    // Single-stroke entries of all sections share one namespace
+    fn defines(&self, chord: &ChordKey) -> bool {
+        [&self.chords, &self.prefixes, &self.suffixes, &self.infixes, &self.exceptions]
+            .iter()
+            .any(|entries| entries.contains_key(chord))
+    }
+
+    /// Single-stroke entries of a section; multi-stroke `[exceptions]` are
+    /// in [`sequences`](Self::sequences)
+    pub fn section(&self, section: Section) -> &HashMap<ChordKey, Expansion> {
+        match section {
+            Section::Chord => &self.chords,
+            Section::Prefix => &self.prefixes,
+            Section::Suffix => &self.suffixes,
+            Section::Infix => &self.infixes,
+            Section::Exception => &self.exceptions,
+        }
+    }
+
+    fn section_mut(&mut self, section: Section) -> &mut HashMap<ChordKey, Expansion> {
+        match section {
+            Section::Chord => &mut self.chords,
+            Section::Prefix => &mut self.prefixes,
+            Section::Suffix => &mut self.suffixes,
+            Section::Infix => &mut self.infixes,
+            Section::Exception => &mut self.exceptions,
+        }
+    }
+
+    pub fn resolve(&self, chord: &[KeyCode]) -> Option<&Expansion> {
+        self.chords.get(&ChordKey::new(chord)?)
+    }
+
+    pub fn resolve_exception(&self, chord: &[KeyCode]) -> Option<&Expansion> {
+        self.exceptions.get(&ChordKey::new(chord)?)
+    }
+
+    pub fn apply_affixes(&self, chord: &[KeyCode]) -> Option<&Expansion> {
+        let chord = ChordKey::new(chord)?;
+        
+        // Try prefix first, then suffix
+        self.prefixes.get(&chord)
+            .or_else(|| self.suffixes.get(&chord))
+    }
+
+    pub fn resolve_infix(&self, chord: &[KeyCode]) -> Option<&Expansion> {
+        self.infixes.get(&ChordKey::new(chord)?)
+    }
+
+    pub fn resolve_shorthand(&self, word: &str) -> Option<&Expansion> {
+        self.shorthands.get(word)
+    }
+}
+
+/// The section of a `.zc` file a chord or sequence is defined in.
+/// Shorthands are keyed by text instead, see [`ChordLibrary::shorthands`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    Prefix,
+    Suffix,
+    Infix,
+    Chord,
+    Exception,
+}
+
+impl Section {
+    /// Every section, in the order `.zc` files are written
+    pub const ALL: [Section; 5] = [
+        Section::Prefix,
+        Section::Suffix,
+        Section::Infix,
+        Section::Chord,
+        Section::Exception,
+    ];
+
+    // Affixes always glue to the word they complete
+    fn glue(self, expansion: &mut Expansion) {
+        match self {
+            Section::Prefix => expansion.attach_right = true,
+            Section::Suffix => expansion.attach_left = true,
+            Section::Infix => {
+                expansion.attach_left = true;
+                expansion.attach_right = true;
+            }
+            Section::Chord | Section::Exception => {}
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Section::Prefix => "prefixes",
+            Section::Suffix => "suffixes",
+            Section::Infix => "infixes",
+            Section::Chord => "chords",
+            Section::Exception => "exceptions",
+        }
+    }
+}
@@ -0,0 +1,424 @@
+use anyhow::Result;
+use evdev::KeyCode;
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use super::diagnostics::{Diagnostic, Diagnostics, Severity};
+use super::document::{Document, LineKind, HEADER};
+use super::expansion::{Expansion, Source};
+use super::layout::Layout;
+use super::chord::join_strokes;
+use super::{ChordKey, ChordLibrary, DuplicatePolicy, Section, FORMAT_VERSION, MAX_CHORD_KEYS};
+
+/// A section header: one of the chord sections, or `[shorthands]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Heading {
+    Chords(Section),
+    Shorthands,
+}
+
+impl Heading {
+    fn name(self) -> &'static str {
+        match self {
+            Heading::Chords(section) => section.name(),
+            Heading::Shorthands => "shorthands",
+        }
+    }
+}
+
+/// What an entry defines: strokes share one namespace, abbreviations another
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Defined {
+    Strokes(Vec<ChordKey>),
+    Shorthand(String),
+}
+
+impl fmt::Display for Defined {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Defined::Strokes(strokes) => f.write_str(&join_strokes(strokes)),
+            Defined::Shorthand(abbreviation) => f.write_str(abbreviation),
+        }
+    }
+}
+
+/// Where an entry was defined, used to report duplicates
+#[derive(Debug, Clone, Copy)]
+struct Definition {
+    heading: Heading,
+    line: usize,
+}
+
+/// An `include:` directive, resolved once the including file is parsed
+#[derive(Debug)]
+pub(crate) struct Include {
+    pub(crate) path: PathBuf,
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+}
+
+pub(crate) struct LibraryParser<'a> {
+    file: Arc<Path>,
+    duplicates: DuplicatePolicy,
+    // Resolves single-character keys such as `t` to physical keys
+    layout: &'a Layout,
+    library: ChordLibrary,
+    current_section: Option<Heading>,
+    diagnostics: Diagnostics,
+    includes: Vec<Include>,
+    defined: HashMap<Defined, Definition>,
+    // Whether a duplicate was reported under `DuplicatePolicy::Error`
+    duplicated: bool,
+}
+
+impl<'a> LibraryParser<'a> {
+    pub(crate) fn new(file: Arc<Path>, duplicates: DuplicatePolicy, layout: &'a Layout) -> Self {
+        Self {
+            file,
+            duplicates,
+            layout,
+            library: ChordLibrary::default(),
+            current_section: None,
+            diagnostics: Diagnostics::default(),
+            includes: Vec::new(),
+            defined: HashMap::new(),
+            duplicated: false,
+        }
+    }
+
+    pub(crate) fn parse(&mut self, content: &str) -> Result<()> {
+        let document = Document::parse(content);
+
+        for (index, line) in document.lines().iter().enumerate() {
+            let number = index + 1;
+            let raw = line.text();
+            let kind = line.kind();
+
+            // Skip empty lines and comments
+            if matches!(kind, LineKind::Blank | LineKind::Comment) {
+                continue;
+            }
+
+            // The format header must come before anything else
+            if self.library.meta.format_version == 0 {
+                self.library.meta.format_version = self.parse_header(raw, kind, number)?;
+                continue;
+            }
+
+            match *kind {
+                LineKind::Blank | LineKind::Comment => {}
+                LineKind::Meta { key, value } => {
+                    let value = value.of(raw);
+                    match key.of(raw) {
+                        "name" => self.library.meta.name = value.to_string(),
+                        "language" => self.library.meta.language = value.to_string(),
+                        "version" => self.library.meta.version = value.to_string(),
+                        _ if value.is_empty() => {
+                            self.report(Severity::Error, number, column_of(raw, raw.trim()),
+                                "Missing file name after `include:`".to_string());
+                        }
+                        _ => self.includes.push(Include {
+                            path: PathBuf::from(value),
+                            line: number,
+                            column: column_of(raw, value),
+                        }),
+                    }
+                }
+                // Parse section headers - order doesn't matter
+                LineKind::Section { name } => {
+                    let name = name.of(raw);
+                    self.current_section = match name.to_lowercase().as_str() {
+                        "prefixes" => Some(Heading::Chords(Section::Prefix)),
+                        "suffixes" => Some(Heading::Chords(Section::Suffix)),
+                        "infixes" => Some(Heading::Chords(Section::Infix)),
+                        "chords" => Some(Heading::Chords(Section::Chord)),
+                        "exceptions" => Some(Heading::Chords(Section::Exception)),
+                        "shorthands" => Some(Heading::Shorthands),
+                        _ => {
+                            self.report(Severity::Warning, number, column_of(raw, name),
+                                format!("Unknown section `[{}]`, its mappings are ignored", name));
+                            None
+                        }
+                    };
+                }
+                // Parse mappings - order within sections doesn't matter
+                LineKind::Entry { keys, value, .. } => {
+                    self.parse_entry(raw, keys.of(raw), value.of(raw), number);
+                }
+                // Ignore all other lines
+                LineKind::Header { .. } | LineKind::Other => {
+                    let line = raw.trim();
+                    self.report(Severity::Warning, number, column_of(raw, line),
+                        format!("Ignoring unrecognised line `{}`", line));
+                }
+            }
+        }
+
+        if self.library.meta.format_version == 0 {
+            self.report(Severity::Error, 1, 1,
+                format!("Missing `{} v{}` header", HEADER, FORMAT_VERSION));
+            return Err(self.fatal());
+        }
+
+        // Duplicates under the error policy fail the file, strict or not
+        if self.duplicated {
+            return Err(self.fatal());
+        }
+
+        Ok(())
+    }
+
+    fn parse_entry(&mut self, raw: &str, key: &str, value: &str, number: usize) {
+        let mut expansion = Expansion::parse(value);
+        expansion.source = Some(Source { file: self.file.clone(), line: number });
+
+        let section = match self.current_section {
+            Some(Heading::Chords(section)) => section,
+            Some(Heading::Shorthands) => {
+                // The abbreviation is typed text, not key names
+                if !self.claim(Heading::Shorthands, Defined::Shorthand(key.to_string()), number, column_of(raw, key)) {
+                    return;
+                }
+                if let Err(e) = self.library.insert_shorthand(key, expansion) {
+                    self.report(Severity::Error, number, column_of(raw, key), e.to_string());
+                }
+                return;
+            }
+            None => {
+                self.report(Severity::Warning, number, column_of(raw, raw.trim()),
+                    "Mapping outside of a known section".to_string());
+                return;
+            }
+        };
+
+        let strokes = match parse_strokes(section, key, self.layout) {
+            Ok(strokes) => strokes,
+            Err((at, message)) => {
+                self.report(Severity::Error, number, column_of(raw, at), message);
+                return;
+            }
+        };
+
+        if !self.claim(Heading::Chords(section), Defined::Strokes(strokes.clone()), number, column_of(raw, key)) {
+            return;
+        }
+
+        // Replaces the entry of a duplicate the policy let through
+        if let Err(e) = self.library.insert(section, &strokes, expansion) {
+            self.report(Severity::Error, number, column_of(raw, key), e.to_string());
+        }
+    }
+
+    fn parse_header(&mut self, raw: &str, kind: &LineKind, number: usize) -> Result<u32> {
+        let version = match *kind {
+            LineKind::Header { version } => version.of(raw).strip_prefix('v'),
+            _ => None,
+        };
+
+        let Some(version) = version else {
+            self.report(Severity::Error, number, column_of(raw, raw.trim()),
+                format!("Missing `{} v{}` header before the first entry", HEADER, FORMAT_VERSION));
+            return Err(self.fatal());
+        };
+
+        match version.parse::<u32>() {
+            Ok(FORMAT_VERSION) => Ok(FORMAT_VERSION),
+            // Older formats get migrated here once the format evolves past v1
+            Ok(other) => {
+                self.report(Severity::Error, number, column_of(raw, version),
+                    format!("Unsupported library format v{} (this version of zipchord reads v{})", other, FORMAT_VERSION));
+                Err(self.fatal())
+            }
+            Err(_) => {
+                self.report(Severity::Error, number, column_of(raw, version),
+                    format!("Invalid library format version `{}`", version));
+                Err(self.fatal())
+            }
+        }
+    }
+
+    // The file can't be read at all; fail with everything reported so far
+    fn fatal(&self) -> anyhow::Error {
+        anyhow::Error::new(self.diagnostics.clone())
+    }
+
+    /// Record a definition, resolving a clash with an earlier one by the
+    /// duplicate policy. Returns whether the new entry should be stored.
+    fn claim(&mut self, heading: Heading, defined: Defined, line: usize, column: usize) -> bool {
+        let Some(previous) = self.defined.get(&defined).copied() else {
+            self.defined.insert(defined, Definition { heading, line });
+            return true;
+        };
+
+        let message = format!(
+            "`{}` is defined twice: line {} in [{}] and line {} in [{}]",
+            defined, previous.line, previous.heading.name(), line, heading.name(),
+        );
+        match self.duplicates {
+            DuplicatePolicy::FirstWins => self.report(Severity::Warning, line, column,
+                format!("{}; keeping line {}", message, previous.line)),
+            DuplicatePolicy::LastWins => self.report(Severity::Warning, line, column,
+                format!("{}; keeping line {}", message, line)),
+            DuplicatePolicy::Error => {
+                self.duplicated = true;
+                self.report(Severity::Error, line, column, message);
+            }
+        }
+
+        if self.duplicates != DuplicatePolicy::LastWins {
+            return false;
+        }
+
+        self.defined.insert(defined, Definition { heading, line });
+        true
+    }
+
+    fn report(&mut self, severity: Severity, line: usize, column: usize, message: String) {
+        self.diagnostics.push(Diagnostic {
+            file: self.file.to_path_buf(),
+            line,
+            column,
+            severity,
+            message,
+        });
+    }
+
+    pub(crate) fn into_library(self) -> (ChordLibrary, Diagnostics, Vec<Include>) {
+        (self.library, self.diagnostics, self.includes)
+    }
+}
+
+// Parse the keys of an entry in `section` into its strokes. Errors point at
+// the offending part of `keys`.
+pub(crate) fn parse_strokes<'k>(section: Section, keys: &'k str, layout: &Layout) -> Result<Vec<ChordKey>, (&'k str, String)> {
+    // Whitespace separates the strokes of an exception sequence
+    match section {
+        Section::Exception => keys.split_whitespace().map(|stroke| parse_chord(stroke, layout)).collect(),
+        _ => parse_chord(keys, layout).map(|chord| vec![chord]),
+    }
+}
+
+// Parse `KEY_A+KEY_B` or `t+h` into a chord
+fn parse_chord<'k>(keys: &'k str, layout: &Layout) -> Result<ChordKey, (&'k str, String)> {
+    let chord = keys.split('+')
+        .map(|name| {
+            let name = name.trim();
+            layout.resolve_key(name).map_err(|message| (name, message))
+        })
+        .collect::<Result<Vec<KeyCode>, _>>()?;
+
+    ChordKey::new(&chord)
+        .ok_or((keys, format!("Chord has more than {} keys", MAX_CHORD_KEYS)))
+}
+
+// 1-based column of `part`, which must be a slice of `line`
+fn column_of(line: &str, part: &str) -> usize {
+    (part.as_ptr() as usize).saturating_sub(line.as_ptr() as usize) + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str, duplicates: DuplicatePolicy) -> (Result<()>, ChordLibrary, Diagnostics) {
+        let layout = Layout::default();
+        let mut parser = LibraryParser::new(Path::new("test.zc").into(), duplicates, &layout);
+        let result = parser.parse(content);
+        let (library, diagnostics, _) = parser.into_library();
+        (result, library, diagnostics)
+    }
+
+    fn chord(keys: &[KeyCode]) -> ChordKey {
+        ChordKey::new(keys).unwrap()
+    }
+
+    #[test]
+    fn characters_follow_the_layout_and_key_names_do_not() {
+        fn strokes(keys: &str, layout: &Layout) -> Vec<ChordKey> {
+            parse_strokes(Section::Chord, keys, layout).unwrap()
+        }
+        let qwerty = Layout::default();
+        let colemak = Layout::builtin("colemak").unwrap();
+
+        assert_eq!(strokes("t+h", &qwerty), strokes("KEY_T+KEY_H", &qwerty));
+        assert_eq!(strokes("t+h", &colemak), vec![chord(&[KeyCode::KEY_F, KeyCode::KEY_H])]);
+        assert_eq!(strokes("KEY_T+KEY_H", &colemak), vec![chord(&[KeyCode::KEY_T, KeyCode::KEY_H])]);
+        assert_eq!(
+            parse_strokes(Section::Chord, "t + ?", &colemak).unwrap_err().0,
+            "?",
+        );
+    }
+
+    #[test]
+    fn entries_go_to_their_section() {
+        let (result, library, diagnostics) = parse(concat!(
+            "ZIPCHORD LIBRARY v1\n",
+            "name: Test\n",
+            "[chords]\n",
+            "KEY_T+KEY_H => the\n",
+            "[prefixes]\n",
+            "d+s => dis\n",
+            "[exceptions]\n",
+            "e . g . => e.g.\n",
+            "[shorthands]\n",
+            "btw => by the way\n",
+        ), DuplicatePolicy::default());
+
+        assert!(result.is_ok());
+        assert!(diagnostics.is_empty(), "{}", diagnostics);
+        assert_eq!(library.meta().name, "Test");
+        assert_eq!(library.resolve(&[KeyCode::KEY_H, KeyCode::KEY_T]).unwrap().text, "the");
+
+        // Affixes glue to the word they complete
+        let prefix = library.apply_affixes(&[KeyCode::KEY_D, KeyCode::KEY_S]).unwrap();
+        assert_eq!((prefix.text.as_str(), prefix.attach_right), ("dis", true));
+
+        let e = chord(&[KeyCode::KEY_E]);
+        let g = chord(&[KeyCode::KEY_G]);
+        let dot = chord(&[KeyCode::KEY_DOT]);
+        assert_eq!(library.sequences()[0].strokes, [e, dot, g, dot]);
+        assert_eq!(library.resolve_shorthand("btw").unwrap().text, "by the way");
+    }
+
+    #[test]
+    fn errors_point_at_the_bad_key() {
+        let (result, library, diagnostics) = parse(
+            "ZIPCHORD LIBRARY v1\n[chords]\nt+zz => the\n",
+            DuplicatePolicy::default(),
+        );
+
+        assert!(result.is_ok());
+        assert!(library.is_empty());
+        let diagnostic = diagnostics.iter().next().unwrap();
+        assert_eq!((diagnostic.line, diagnostic.column, diagnostic.severity), (3, 3, Severity::Error));
+    }
+
+    #[test]
+    fn missing_header_fails() {
+        let (result, _, diagnostics) = parse("[chords]\nt+h => the\n", DuplicatePolicy::default());
+
+        assert!(result.is_err());
+        assert!(diagnostics.has_errors());
+    }
+
+    #[test]
+    fn duplicates_follow_the_policy() {
+        let content = "ZIPCHORD LIBRARY v1\n[chords]\nt+h => the\nh+t => then\n";
+        let th = [KeyCode::KEY_T, KeyCode::KEY_H];
+
+        let (_, library, _) = parse(content, DuplicatePolicy::FirstWins);
+        assert_eq!(library.resolve(&th).unwrap().text, "the");
+
+        let (_, library, diagnostics) = parse(content, DuplicatePolicy::LastWins);
+        assert_eq!(library.resolve(&th).unwrap().text, "then");
+        assert!(!diagnostics.has_errors());
+
+        let (result, _, diagnostics) = parse(content, DuplicatePolicy::Error);
+        assert!(result.is_err());
+        assert!(diagnostics.has_errors());
+    }
+}
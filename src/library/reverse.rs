@@ -0,0 +1,210 @@
+use std::{cmp::Ordering, collections::HashMap, fmt};
+
+use super::{join_strokes, ChordKey, ChordLibrary, Section, Source};
+
+/// An entry producing some text, as found by [`ChordLibrary::lookup`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Origin {
+    /// One chord, or the strokes of an `[exceptions]` sequence
+    Chord {
+        strokes: Vec<ChordKey>,
+        section: Section,
+        /// The library file and line defining the entry
+        source: Option<Source>,
+    },
+    /// An abbreviation of the `[shorthands]` section
+    Shorthand {
+        abbreviation: String,
+        source: Option<Source>,
+    },
+}
+
+impl Origin {
+    /// The library file and line defining the entry
+    pub fn source(&self) -> Option<&Source> {
+        match self {
+            Origin::Chord { source, .. } | Origin::Shorthand { source, .. } => source.as_ref(),
+        }
+    }
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Chord { strokes, section, .. } => write!(f, "{} [{}]", join_strokes(strokes), section.name())?,
+            Origin::Shorthand { abbreviation, .. } => write!(f, "{} [shorthands]", abbreviation)?,
+        }
+        if let Some(source) = self.source() {
+            write!(f, " {}", source)?;
+        }
+        Ok(())
+    }
+}
+
+/// Every entry of a library keyed by the text it types
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ReverseIndex {
+    origins: HashMap<String, Vec<Origin>>,
+}
+
+impl ReverseIndex {
+    pub(crate) fn build(library: &ChordLibrary) -> Self {
+        let mut index = Self::default();
+
+        for section in Section::ALL {
+            for (chord, expansion) in library.section(section) {
+                index.add(&expansion.text, Origin::Chord {
+                    strokes: vec![*chord],
+                    section,
+                    source: expansion.source.clone(),
+                });
+            }
+        }
+        for sequence in &library.sequences {
+            index.add(&sequence.expansion.text, Origin::Chord {
+                strokes: sequence.strokes.clone(),
+                section: Section::Exception,
+                source: sequence.expansion.source.clone(),
+            });
+        }
+        for (abbreviation, expansion) in &library.shorthands {
+            index.add(&expansion.text, Origin::Shorthand {
+                abbreviation: abbreviation.clone(),
+                source: expansion.source.clone(),
+            });
+        }
+
+        for origins in index.origins.values_mut() {
+            origins.sort_by(shortest_first);
+        }
+        index
+    }
+
+    fn add(&mut self, text: &str, origin: Origin) {
+        self.origins.entry(text.to_string()).or_default().push(origin);
+    }
+
+    pub(crate) fn insert(&mut self, text: &str, origin: Origin) {
+        let origins = self.origins.entry(text.to_string()).or_default();
+        let at = origins.partition_point(|other| shortest_first(other, &origin).is_lt());
+        origins.insert(at, origin);
+    }
+
+    /// Forget the chord or sequence typing `text` with `strokes`
+    pub(crate) fn remove(&mut self, text: &str, strokes: &[ChordKey]) {
+        self.remove_where(text, |origin| matches!(origin, Origin::Chord { strokes: other, .. } if other == strokes));
+    }
+
+    /// Forget the shorthand typing `text` from `abbreviation`
+    pub(crate) fn remove_shorthand(&mut self, text: &str, abbreviation: &str) {
+        self.remove_where(text, |origin| matches!(origin, Origin::Shorthand { abbreviation: other, .. } if other == abbreviation));
+    }
+
+    fn remove_where(&mut self, text: &str, found: impl Fn(&Origin) -> bool) {
+        let Some(origins) = self.origins.get_mut(text) else {
+            return;
+        };
+        origins.retain(|origin| !found(origin));
+        if origins.is_empty() {
+            self.origins.remove(text);
+        }
+    }
+
+    pub(crate) fn get(&self, text: &str) -> &[Origin] {
+        self.origins.get(text).map_or(&[], Vec::as_slice)
+    }
+}
+
+// Shortest first, so the easiest chord for a word is listed first; chords
+// come before shorthands, which take a whole word to type
+fn shortest_first(a: &Origin, b: &Origin) -> Ordering {
+    match (a, b) {
+        (Origin::Chord { strokes: a, .. }, Origin::Chord { strokes: b, .. }) => (a.len(), a).cmp(&(b.len(), b)),
+        (Origin::Shorthand { abbreviation: a, .. }, Origin::Shorthand { abbreviation: b, .. }) => {
+            (a.chars().count(), a).cmp(&(b.chars().count(), b))
+        }
+        (Origin::Chord { .. }, Origin::Shorthand { .. }) => Ordering::Less,
+        (Origin::Shorthand { .. }, Origin::Chord { .. }) => Ordering::Greater,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::{Expansion, Layout};
+
+    fn strokes(keys: &str) -> Vec<ChordKey> {
+        crate::library::parser::parse_strokes(Section::Exception, keys, &Layout::default()).unwrap()
+    }
+
+    // The lookup of every text as `(strokes or abbreviation, section)`
+    fn found(library: &ChordLibrary, text: &str) -> Vec<(String, &'static str)> {
+        library.lookup(text).iter()
+            .map(|origin| match origin {
+                Origin::Chord { strokes, section, .. } => (join_strokes(strokes), section.name()),
+                Origin::Shorthand { abbreviation, .. } => (abbreviation.clone(), "shorthands"),
+            })
+            .collect()
+    }
+
+    fn library() -> ChordLibrary {
+        ChordLibrary::builder()
+            .chord("t+h+e", "the").unwrap()
+            .entry(Section::Exception, "t+h a+b", "the").unwrap()
+            .chord("t+h", "the").unwrap()
+            .entry(Section::Suffix, "i+g", "ing").unwrap()
+            .shorthand("teh", "the").unwrap()
+            .shorthand("th", "the").unwrap()
+            .build()
+    }
+
+    #[test]
+    fn lookup_lists_the_shortest_entry_first() {
+        let library = library();
+        let the = found(&library, "the");
+        let expected = [
+            (join_strokes(&strokes("t+h")), "chords"),
+            (join_strokes(&strokes("t+h+e")), "chords"),
+            (join_strokes(&strokes("t+h a+b")), "exceptions"),
+            ("th".to_string(), "shorthands"),
+            ("teh".to_string(), "shorthands"),
+        ];
+        assert_eq!(the, expected);
+
+        // Affixes are found by their text, without glue markers
+        assert_eq!(found(&library, "ing").len(), 1);
+        assert!(found(&library, "~ing").is_empty());
+        assert!(found(&library, "them").is_empty());
+        assert_eq!(library.lookup("the")[3].to_string(), "th [shorthands]");
+    }
+
+    #[test]
+    fn edits_keep_a_built_index_in_sync() {
+        let mut library = library();
+        assert_eq!(found(&library, "the").len(), 5);
+
+        library.insert(Section::Chord, &strokes("a+b"), Expansion::parse("the")).unwrap();
+        library.insert(Section::Chord, &strokes("t+h"), Expansion::parse("then")).unwrap();
+        library.remove(&strokes("t+h+e"));
+        library.remove(&strokes("t+h a+b"));
+        library.insert_shorthand("th", Expansion::parse("they")).unwrap();
+        library.remove_shorthand("teh");
+        library.insert(Section::Prefix, &strokes("i+g"), Expansion::parse("ing")).unwrap();
+
+        assert_eq!(found(&library, "the"), [(join_strokes(&strokes("a+b")), "chords")]);
+        assert_eq!(found(&library, "then"), [(join_strokes(&strokes("t+h")), "chords")]);
+        assert_eq!(found(&library, "they"), [("th".to_string(), "shorthands")]);
+        assert_eq!(found(&library, "ing"), [(join_strokes(&strokes("i+g")), "prefixes")]);
+
+        // The same as an index built from scratch
+        let rebuilt = ReverseIndex::build(&library);
+        for text in ["the", "then", "they", "ing", "teh"] {
+            assert_eq!(library.lookup(text), rebuilt.get(text), "{}", text);
+        }
+        assert!(library.lookup("teh").is_empty());
+
+        // Bulk changes drop the index, the next lookup builds it again
+        library.merge(ChordLibrary::builder().shorthand("teh", "the").unwrap().build());
+        assert_eq!(found(&library, "the")[1], ("teh".to_string(), "shorthands"));
+    }
+}
@@ -0,0 +1,290 @@
+use evdev::KeyCode;
+use std::{collections::VecDeque, time::Duration};
+
+use super::expansion::Expansion;
+use super::{ChordKey, ChordLibrary};
+use crate::stream::Timestamp;
+
+/// How long a half-typed sequence is kept before it is abandoned
+pub const DEFAULT_SEQUENCE_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// An ordered multi-stroke entry such as `e . g . => e.g.`
+#[derive(Debug, Clone)]
+pub struct Sequence {
+    pub strokes: Vec<ChordKey>,
+    pub expansion: Expansion,
+}
+
+/// Result of a completed sequence
+#[derive(Debug)]
+pub struct SequenceMatch<'a> {
+    pub expansion: &'a Expansion,
+    /// Characters the strokes of the sequence left on screen
+    pub erase: usize,
+}
+
+#[derive(Debug)]
+struct Stroke {
+    chord: ChordKey,
+    typed: usize,
+    at: Timestamp,
+}
+
+/// Rolling history of recent strokes, matched against the library's sequences.
+///
+/// Strokes come from raw key presses, so typing that overlaps too much to
+/// be detected as anything still counts: each press is a one-key stroke.
+/// A chord detected on release replaces the presses of its keys with one
+/// stroke.
+#[derive(Debug)]
+pub struct SequenceMatcher {
+    history: VecDeque<Stroke>,
+    timeout: Duration,
+    // Ctrl, Alt and Meta keys held down; keys pressed with them type nothing
+    modifiers: Vec<KeyCode>,
+}
+
+impl SequenceMatcher {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            history: VecDeque::new(),
+            timeout,
+            modifiers: Vec::new(),
+        }
+    }
+
+    /// Record a key press or release and return the sequence the press
+    /// completes, if any.
+    ///
+    /// The key is assumed to have typed one character; call
+    /// [`record_output`](Self::record_output) when it was expanded instead.
+    pub fn key<'a>(
+        &mut self,
+        library: &'a ChordLibrary,
+        key: KeyCode,
+        pressed: bool,
+        now: Timestamp,
+    ) -> Option<SequenceMatch<'a>> {
+        match key {
+            KeyCode::KEY_LEFTSHIFT | KeyCode::KEY_RIGHTSHIFT => return None,
+            KeyCode::KEY_LEFTCTRL | KeyCode::KEY_RIGHTCTRL
+            | KeyCode::KEY_LEFTALT | KeyCode::KEY_RIGHTALT
+            | KeyCode::KEY_LEFTMETA | KeyCode::KEY_RIGHTMETA => {
+                self.modifiers.retain(|held| *held != key);
+                if pressed {
+                    self.modifiers.push(key);
+                    self.reset();
+                }
+                return None;
+            }
+            _ if !pressed => return None,
+            KeyCode::KEY_BACKSPACE => {
+                self.reset();
+                return None;
+            }
+            _ if !self.modifiers.is_empty() => {
+                self.reset();
+                return None;
+            }
+            _ => {}
+        }
+
+        let chord = ChordKey::new(&[key])?;
+        self.push(library, Stroke { chord, typed: 1, at: now })
+    }
+
+    /// Record a chord detected on release and return the sequence it
+    /// completes, if any.
+    ///
+    /// The presses of its keys are taken back into the one stroke. A single
+    /// key is already recorded by its press and is ignored.
+    pub fn chord<'a>(
+        &mut self,
+        library: &'a ChordLibrary,
+        keys: &[KeyCode],
+        now: Timestamp,
+    ) -> Option<SequenceMatch<'a>> {
+        if keys.len() < 2 {
+            return None;
+        }
+        let Some(chord) = ChordKey::new(keys) else {
+            self.reset();
+            return None;
+        };
+
+        // The presses of the chord's keys are the latest one-key strokes
+        let mut typed = 0;
+        let mut taken: Vec<KeyCode> = Vec::with_capacity(keys.len());
+        while let Some(last) = self.history.back() {
+            let key = last.chord.keys().next();
+            match key {
+                Some(key) if last.chord.len() == 1 && keys.contains(&key) && !taken.contains(&key) => {
+                    taken.push(key);
+                    typed += last.typed;
+                    self.history.pop_back();
+                }
+                _ => break,
+            }
+        }
+
+        // Keys whose press was not seen still typed their character
+        let typed = typed + keys.len() - taken.len();
+        self.push(library, Stroke { chord, typed, at: now })
+    }
+
+    // Add a stroke and match the history ending with it
+    fn push<'a>(&mut self, library: &'a ChordLibrary, stroke: Stroke) -> Option<SequenceMatch<'a>> {
+        // Abandon a half-typed sequence
+        if self.history.back().is_some_and(|last| stroke.at.duration_since(last.at) > self.timeout) {
+            self.reset();
+        }
+        self.history.push_back(stroke);
+
+        let longest = library.sequences.iter().map(|s| s.strokes.len()).max().unwrap_or(0);
+        while self.history.len() > longest {
+            self.history.pop_front();
+        }
+
+        // Prefer the longest sequence ending with this stroke
+        let sequence = library
+            .sequences
+            .iter()
+            .filter(|s| self.ends_with(&s.strokes))
+            .max_by_key(|s| s.strokes.len())?;
+
+        let erase = self
+            .history
+            .iter()
+            .rev()
+            .take(sequence.strokes.len())
+            .map(|s| s.typed)
+            .sum();
+        self.reset();
+
+        Some(SequenceMatch {
+            expansion: &sequence.expansion,
+            erase,
+        })
+    }
+
+    /// Set the number of characters the last stroke actually left on screen
+    pub fn record_output(&mut self, chars: usize) {
+        if let Some(last) = self.history.back_mut() {
+            last.typed = chars;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.history.clear();
+    }
+
+    fn ends_with(&self, strokes: &[ChordKey]) -> bool {
+        strokes.len() <= self.history.len()
+            && self
+                .history
+                .iter()
+                .rev()
+                .zip(strokes.iter().rev())
+                .all(|(stroke, chord)| &stroke.chord == chord)
+    }
+}
+
+impl Default for SequenceMatcher {
+    fn default() -> Self {
+        Self::new(DEFAULT_SEQUENCE_TIMEOUT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::Section;
+
+    fn library() -> ChordLibrary {
+        ChordLibrary::builder()
+            .chord("t+h", "the").unwrap()
+            .entry(Section::Exception, "e . g .", "e.g.").unwrap()
+            .entry(Section::Exception, "t+h s", "these").unwrap()
+            .build()
+    }
+
+    fn at(ms: u64) -> Timestamp {
+        Timestamp::from_duration(Duration::from_millis(ms))
+    }
+
+    // Press and release each key 30ms apart, returning what the last press completed
+    fn type_keys<'a>(
+        matcher: &mut SequenceMatcher,
+        library: &'a ChordLibrary,
+        keys: &[KeyCode],
+        start: u64,
+    ) -> Option<SequenceMatch<'a>> {
+        let mut found = None;
+        for (i, key) in keys.iter().enumerate() {
+            let ms = start + 30 * i as u64;
+            found = matcher.key(library, *key, true, at(ms));
+            matcher.key(library, *key, false, at(ms + 50));
+        }
+        found
+    }
+
+    const EG: [KeyCode; 4] = [KeyCode::KEY_E, KeyCode::KEY_DOT, KeyCode::KEY_G, KeyCode::KEY_DOT];
+
+    #[test]
+    fn presses_complete_a_sequence() {
+        let library = library();
+        let mut matcher = SequenceMatcher::default();
+
+        let found = type_keys(&mut matcher, &library, &EG, 0).unwrap();
+        assert_eq!((found.expansion.text.as_str(), found.erase), ("e.g.", 4));
+    }
+
+    #[test]
+    fn chord_replaces_the_presses_of_its_keys() {
+        let library = library();
+        let mut matcher = SequenceMatcher::default();
+
+        matcher.key(&library, KeyCode::KEY_T, true, at(0));
+        matcher.key(&library, KeyCode::KEY_H, true, at(5));
+        assert!(matcher.chord(&library, &[KeyCode::KEY_T, KeyCode::KEY_H], at(80)).is_none());
+        // The chord was expanded to "the "
+        matcher.record_output(4);
+
+        let found = type_keys(&mut matcher, &library, &[KeyCode::KEY_S], 200).unwrap();
+        assert_eq!((found.expansion.text.as_str(), found.erase), ("these", 5));
+    }
+
+    #[test]
+    fn single_key_chords_are_ignored() {
+        let library = library();
+        let mut matcher = SequenceMatcher::default();
+
+        type_keys(&mut matcher, &library, &EG[..3], 0);
+        assert!(matcher.chord(&library, &[KeyCode::KEY_G], at(200)).is_none());
+        assert!(type_keys(&mut matcher, &library, &EG[3..], 300).is_some());
+    }
+
+    #[test]
+    fn pause_abandons_the_sequence() {
+        let library = library();
+        let mut matcher = SequenceMatcher::new(Duration::from_millis(500));
+
+        type_keys(&mut matcher, &library, &EG[..2], 0);
+        assert!(type_keys(&mut matcher, &library, &EG[2..], 1000).is_none());
+    }
+
+    #[test]
+    fn backspace_and_modifiers_reset() {
+        let library = library();
+        let mut matcher = SequenceMatcher::default();
+
+        type_keys(&mut matcher, &library, &EG[..2], 0);
+        type_keys(&mut matcher, &library, &[KeyCode::KEY_BACKSPACE], 100);
+        assert!(type_keys(&mut matcher, &library, &EG[2..], 200).is_none());
+
+        type_keys(&mut matcher, &library, &EG[..2], 300);
+        matcher.key(&library, KeyCode::KEY_LEFTCTRL, true, at(400));
+        matcher.key(&library, KeyCode::KEY_LEFTCTRL, false, at(410));
+        assert!(type_keys(&mut matcher, &library, &EG[2..], 500).is_none());
+    }
+}
@@ -0,0 +1,203 @@
+use anyhow::{bail, ensure, Result};
+use evdev::KeyCode;
+
+use super::document::{Document, LineKind};
+use super::expansion::Expansion;
+use super::layout::{Keystroke, Layout};
+use super::ChordLibrary;
+
+/// Characters that end a word, expanding the shorthand typed before them
+pub const SHORTHAND_DELIMITERS: [char; 8] = [' ', '\t', '\n', '.', ',', ';', '!', '?'];
+
+/// Result of a shorthand completed by a delimiter
+#[derive(Debug)]
+pub struct ShorthandMatch<'a> {
+    pub expansion: &'a Expansion,
+    /// Characters the abbreviation and its delimiter left on screen
+    pub erase: usize,
+    pub delimiter: char,
+}
+
+impl ShorthandMatch<'_> {
+    /// Text replacing the erased characters: the expansion followed by the
+    /// delimiter, which a trailing `~` swallows
+    pub fn output(&self) -> String {
+        if self.expansion.attach_right {
+            self.expansion.text.clone()
+        } else {
+            format!("{}{}", self.expansion.text, self.delimiter)
+        }
+    }
+}
+
+/// The word being typed, rebuilt from raw key events through a layout and
+/// matched against the library's shorthands when a delimiter is typed
+#[derive(Debug)]
+pub struct ShorthandBuffer {
+    layout: Layout,
+    word: String,
+    // Left and right Shift
+    shift: [bool; 2],
+    // Ctrl, Alt and Meta keys held down; keys pressed with them type nothing
+    modifiers: Vec<KeyCode>,
+}
+
+impl ShorthandBuffer {
+    pub fn new(layout: Layout) -> Self {
+        Self {
+            layout,
+            word: String::new(),
+            shift: [false; 2],
+            modifiers: Vec::new(),
+        }
+    }
+
+    /// Record a key press or release and return the shorthand it completes, if any
+    pub fn key<'a>(&mut self, library: &'a ChordLibrary, key: KeyCode, pressed: bool) -> Option<ShorthandMatch<'a>> {
+        match key {
+            KeyCode::KEY_LEFTSHIFT | KeyCode::KEY_RIGHTSHIFT => {
+                self.shift[usize::from(key == KeyCode::KEY_RIGHTSHIFT)] = pressed;
+                return None;
+            }
+            KeyCode::KEY_LEFTCTRL | KeyCode::KEY_RIGHTCTRL
+            | KeyCode::KEY_LEFTALT | KeyCode::KEY_RIGHTALT
+            | KeyCode::KEY_LEFTMETA | KeyCode::KEY_RIGHTMETA => {
+                self.modifiers.retain(|held| *held != key);
+                if pressed {
+                    self.modifiers.push(key);
+                    self.reset();
+                }
+                return None;
+            }
+            _ if !pressed => return None,
+            _ if !self.modifiers.is_empty() => {
+                self.reset();
+                return None;
+            }
+            KeyCode::KEY_BACKSPACE => {
+                self.word.pop();
+                return None;
+            }
+            _ => {}
+        }
+
+        let typed = match key {
+            KeyCode::KEY_ENTER | KeyCode::KEY_KPENTER => Some('\n'),
+            KeyCode::KEY_TAB => Some('\t'),
+            _ => self.layout.character(Keystroke { key, shift: self.shift.contains(&true) }),
+        };
+        // Arrows, Escape and the like move away from the word
+        let Some(c) = typed else {
+            self.reset();
+            return None;
+        };
+        if !SHORTHAND_DELIMITERS.contains(&c) {
+            self.word.push(c);
+            return None;
+        }
+
+        let word = std::mem::take(&mut self.word);
+        let expansion = library.resolve_shorthand(&word)?;
+        Some(ShorthandMatch {
+            expansion,
+            erase: word.chars().count() + 1,
+            delimiter: c,
+        })
+    }
+
+    /// Forget the word typed so far
+    pub fn reset(&mut self) {
+        self.word.clear();
+    }
+}
+
+/// Check that an abbreviation can be typed as one word and written to a `.zc` file
+pub(crate) fn check_abbreviation(abbreviation: &str) -> Result<()> {
+    ensure!(!abbreviation.is_empty(), "A shorthand needs an abbreviation");
+    if let Some(c) = abbreviation.chars().find(|c| SHORTHAND_DELIMITERS.contains(c) || c.is_whitespace()) {
+        bail!("Shorthand `{}` contains {:?}, which ends a word", abbreviation, c);
+    }
+    // It must read back as the keys of an entry, not as a comment or `name:` line
+    let line = format!("{} => _", abbreviation);
+    let document = Document::parse(&line);
+    let readable = matches!(document.lines()[0].kind(), LineKind::Entry { keys, .. } if keys.of(&line) == abbreviation);
+    ensure!(readable, "Shorthand `{}` can't be written to a .zc library", abbreviation);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library() -> ChordLibrary {
+        ChordLibrary::builder()
+            .shorthand("btw", "by the way").unwrap()
+            .shorthand("Pt", "Portugal").unwrap()
+            .shorthand("afaik", "as far as I know~").unwrap()
+            .build()
+    }
+
+    // Press and release each key, returning what the last one completed
+    fn type_keys<'a>(buffer: &mut ShorthandBuffer, library: &'a ChordLibrary, keys: &[KeyCode]) -> Option<ShorthandMatch<'a>> {
+        let mut found = None;
+        for key in keys {
+            found = buffer.key(library, *key, true);
+            buffer.key(library, *key, false);
+        }
+        found
+    }
+
+    #[test]
+    fn delimiter_completes_the_abbreviation() {
+        let library = library();
+        let mut buffer = ShorthandBuffer::new(Layout::default());
+
+        let found = type_keys(&mut buffer, &library, &[KeyCode::KEY_B, KeyCode::KEY_T, KeyCode::KEY_W, KeyCode::KEY_DOT]).unwrap();
+        assert_eq!((found.erase, found.delimiter), (4, '.'));
+        assert_eq!(found.output(), "by the way.");
+    }
+
+    #[test]
+    fn trailing_glue_swallows_the_delimiter() {
+        let library = library();
+        let mut buffer = ShorthandBuffer::new(Layout::default());
+
+        let keys = [KeyCode::KEY_A, KeyCode::KEY_F, KeyCode::KEY_A, KeyCode::KEY_I, KeyCode::KEY_K, KeyCode::KEY_SPACE];
+        assert_eq!(type_keys(&mut buffer, &library, &keys).unwrap().output(), "as far as I know");
+    }
+
+    #[test]
+    fn shift_types_capitals() {
+        let library = library();
+        let mut buffer = ShorthandBuffer::new(Layout::default());
+
+        buffer.key(&library, KeyCode::KEY_LEFTSHIFT, true);
+        type_keys(&mut buffer, &library, &[KeyCode::KEY_P]);
+        buffer.key(&library, KeyCode::KEY_LEFTSHIFT, false);
+        let found = type_keys(&mut buffer, &library, &[KeyCode::KEY_T, KeyCode::KEY_SPACE]).unwrap();
+        assert_eq!(found.expansion.text, "Portugal");
+    }
+
+    #[test]
+    fn backspace_edits_the_word() {
+        let library = library();
+        let mut buffer = ShorthandBuffer::new(Layout::default());
+
+        let keys = [KeyCode::KEY_B, KeyCode::KEY_T, KeyCode::KEY_X, KeyCode::KEY_BACKSPACE, KeyCode::KEY_W, KeyCode::KEY_SPACE];
+        assert_eq!(type_keys(&mut buffer, &library, &keys).unwrap().erase, 4);
+    }
+
+    #[test]
+    fn other_words_and_shortcuts_do_not_expand() {
+        let library = library();
+        let mut buffer = ShorthandBuffer::new(Layout::default());
+
+        assert!(type_keys(&mut buffer, &library, &[KeyCode::KEY_A, KeyCode::KEY_B, KeyCode::KEY_T, KeyCode::KEY_W, KeyCode::KEY_SPACE]).is_none());
+
+        type_keys(&mut buffer, &library, &[KeyCode::KEY_B, KeyCode::KEY_T]);
+        buffer.key(&library, KeyCode::KEY_LEFTCTRL, true);
+        type_keys(&mut buffer, &library, &[KeyCode::KEY_C]);
+        buffer.key(&library, KeyCode::KEY_LEFTCTRL, false);
+        assert!(type_keys(&mut buffer, &library, &[KeyCode::KEY_W, KeyCode::KEY_SPACE]).is_none());
+    }
+}
@@ -1,46 +1,178 @@
+mod cli;
 mod config;
 mod text_injector;
-use anyhow::{Context, Result};
+mod watcher;
+use anyhow::{bail, Context, Result};
+use cli::{Command, Conversion, Tuning, USAGE};
 use config::AppConfig;
-use log::{error, info};
+use log::{error, info, warn};
 use text_injector::TextInjector;
-use zipchord::stream::{ChordStream, ChordConfig};
-use zipchord::ChordLibrary;
+use watcher::watch_libraries;
+use zipchord::stream::{
+    self, ChordStream, EventSource, KeyEvent, KeyboardListener, Sample, SampleKind, StreamEvent,
+    TraceSource, TraceWriter,
+};
+use zipchord::convert::{self, ConvertOptions, Format};
+use zipchord::library::{Diagnostic, Diagnostics, Keystroke, Layout, LoadOptions, Section, Severity};
+use zipchord::{ChordLibrary, Expansion, SequenceMatcher, ShorthandBuffer};
 use evdev::KeyCode;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
 use std::time::Duration;
 
+// How many words and chords calibration asks for
+const CALIBRATION_PROMPTS: usize = 10;
+
 fn main() -> Result<()> {
     simple_logger::SimpleLogger::new()
         .init()
         .context("Failed to initialize logger")?;
 
-    info!("Starting ZipChord");
+    let command = Command::parse(std::env::args().skip(1))?;
+    if let Command::Help = command {
+        println!("{}", USAGE);
+        return Ok(());
+    }
 
-    let config = AppConfig::load()?;
+    let mut config = AppConfig::load()?;
     info!("Loaded config: {:?}", config);
 
-    let library = ChordLibrary::load(&config.library_path.join("english.zc"))?;
-    info!("Loaded library: {}", library.meta.name);
+    match command {
+        Command::Run(tuning) => {
+            tune(&mut config, &tuning)?;
+            run(config)
+        }
+        Command::Record { output, redact } => record(&config, &output, redact),
+        Command::Replay { trace, tuning } => {
+            tune(&mut config, &tuning)?;
+            replay(&config, &trace)
+        }
+        Command::Calibrate => calibrate(&config),
+        Command::Compile { files } => compile(&config, files),
+        Command::Lookup { words } => lookup(&config, &words),
+        Command::Import(conversion) => import(&config, &conversion),
+        Command::Export(conversion) => export(&config, &conversion),
+        Command::Help => Ok(()),
+    }
+}
+
+fn run(config: AppConfig) -> Result<()> {
+    info!("Starting ZipChord");
+
+    let (mut library, mut accepted) = load_reported(&config)?;
+
+    // A reload adding errors is rejected, so a half-finished edit never
+    // replaces a working library; errors it already had don't block it
+    let reload_config = config.clone();
+    let reloads = watch_libraries(&config.library_path, move || {
+        let (library, diagnostics) = load_reported(&reload_config)?;
+        let added = added_errors(&accepted, &diagnostics);
+        if added > 0 {
+            bail!("The change adds {} error(s)", added);
+        }
+        accepted = diagnostics;
+        Ok(library)
+    })?;
 
     let injector = TextInjector::new()?;
+    let mut typing = Typing::new(config.sequence_timeout, config.layout.clone());
+    let mut chord_stream = ChordStream::new(config.detection.clone())?;
 
-    // Create chord stream with default configuration
-    // let mut chord_stream = ChordStream::with_default_config()?;
+    chord_stream.process_events(move |event| {
+        // Swap in reloaded libraries between events
+        while let Ok(reloaded) = reloads.try_recv() {
+            library = reloaded;
+            typing.sequences.reset();
+            typing.shorthands.reset();
+            info!("Reloaded chord libraries");
+        }
 
-    // Use custom configuration
-    let custom_config = ChordConfig {
-        base_chord_window: Duration::from_millis(150),
-        roll_threshold: 0.7,
-        typing_speed_factor: 0.5,
-        min_overlap_ratio: 0.3,
-    };
-    let mut chord_stream = ChordStream::new(custom_config)?;
+        let Some(edit) = typing.handle(&library, event) else {
+            return;
+        };
+        if let Err(e) = injector.inject_backspaces(edit.erase) {
+            eprintln!("Error injecting backspaces: {}", e);
+        }
+        if let Err(e) = injector.inject(&edit.text) {
+            error!("Injection failed: {}", e);
+        }
+    })?;
+
+    Ok(())
+}
 
-    let mut last_char_was_space = true;
+/// What `run` keeps track of between events to edit the text being typed
+struct Typing {
+    sequences: SequenceMatcher,
+    shorthands: ShorthandBuffer,
+    last_char_was_space: bool,
+    // Whether the character before the cursor is a space that `~` glue may remove
+    space_before: bool,
+    // Whether the last expansion glues to what comes next, so a chord may follow it
+    glued_right: bool,
+    // The key a shorthand or sequence was expanded on, whose release types nothing more
+    consumed: Option<KeyCode>,
+}
+
+/// Text replacing the `erase` characters before the cursor
+#[derive(Debug, PartialEq, Eq)]
+struct Edit {
+    erase: usize,
+    text: String,
+}
 
-    chord_stream.process_events(move |chord| {
+impl Typing {
+    fn new(sequence_timeout: Duration, layout: Layout) -> Self {
+        Self {
+            sequences: SequenceMatcher::new(sequence_timeout),
+            shorthands: ShorthandBuffer::new(layout),
+            last_char_was_space: true,
+            space_before: false,
+            glued_right: false,
+            consumed: None,
+        }
+    }
+
+    /// The edit an event calls for, if any
+    fn handle(&mut self, library: &ChordLibrary, event: StreamEvent) -> Option<Edit> {
+        let (chord, time) = match event {
+            StreamEvent::Key(KeyEvent { key, pressed, time }) => {
+                // A word delimiter completes the shorthand typed before it
+                if let Some(found) = self.shorthands.key(library, key, pressed) {
+                    let edit = self.expand(found.expansion, found.erase, found.output());
+                    self.sequences.reset();
+                    self.consumed = Some(key);
+                    return Some(edit);
+                }
+                // A completed multi-stroke sequence replaces everything its strokes typed
+                let found = self.sequences.key(library, key, pressed, time)?;
+                let edit = self.expand(found.expansion, found.erase, found.expansion.output());
+                self.consumed = Some(key);
+                return Some(edit);
+            }
+            StreamEvent::Chord { keys, time } => (keys, time),
+        };
         info!("Detected chord: {:?}", chord);
 
+        // The expansion already replaced what the key typed
+        if self.consumed.take().is_some_and(|key| chord == [key]) {
+            return None;
+        }
+
+        let had_space_before = self.space_before;
+        if chord.len() == 1 {
+            self.space_before = chord[0] == KeyCode::KEY_SPACE;
+            // A typed character ends what the last expansion glued to
+            self.glued_right = false;
+        }
+
+        if let Some(found) = self.sequences.chord(library, &chord, time) {
+            return Some(self.expand(found.expansion, found.erase, found.expansion.output()));
+        }
+
         // Check if the chord contains only a space or punctuation key
         if chord.len() == 1 {
             match chord[0] {
@@ -51,38 +183,399 @@ fn main() -> Result<()> {
                 KeyCode::KEY_APOSTROPHE |
                 KeyCode::KEY_GRAVE => {
 
-                        last_char_was_space = true;
+                        self.last_char_was_space = true;
                     }
                 
                 _ => {}
             }
         }
 
-        // Check if we're in the middle of a word
-        if !last_char_was_space {
+        // Check if we're in the middle of a word, which a prefix or infix
+        // glued right leaves room to complete
+        if !self.last_char_was_space && !self.glued_right {
             info!("Ignoring chord in the middle of a word");
-            return;
+            return None;
         }
 
-        let expansion = library.resolve(&chord)
-                .or_else(|| library.resolve_exception(&chord))
-                .or_else(|| library.apply_affixes(&chord));
+        let expansion = resolve(library, &chord)?;
+        let backspaces = chord.len() + usize::from(expansion.attach_left && had_space_before);
+        Some(self.expand(expansion, backspaces, expansion.output()))
+    }
 
-        if let Some(text) = expansion {
-            let text = text.to_string();
-            if let Err(e) = injector.inject_backspaces(chord.len()) {
-                eprintln!("Error injecting backspaces: {}", e);
-            }
-            if let Err(e) = injector.inject(&text) {
-                error!("Injection failed: {}", e);
+    /// Replace the `erase` characters before the cursor with `text`, typed for `expansion`
+    fn expand(&mut self, expansion: &Expansion, erase: usize, text: String) -> Edit {
+        if let Some(source) = &expansion.source {
+            info!("Expanding {:?} from {}", expansion.text, source);
+        }
+
+        self.shorthands.reset();
+        self.sequences.record_output(text.chars().count());
+        self.space_before = text.ends_with(' ');
+        // Update last_char_was_space based on the last character of the injected text
+        self.last_char_was_space = ends_word(&text);
+        self.glued_right = expansion.attach_right;
+        Edit { erase, text }
+    }
+}
+
+// The entry a chord types, trying each section in turn
+fn resolve<'a>(library: &'a ChordLibrary, chord: &[KeyCode]) -> Option<&'a Expansion> {
+    library.resolve(chord)
+        .or_else(|| library.resolve_exception(chord))
+        .or_else(|| library.apply_affixes(chord))
+        .or_else(|| library.resolve_infix(chord))
+}
+
+// Whether injected text leaves the cursor at a word boundary
+fn ends_word(text: &str) -> bool {
+    text.chars().last()
+        .map(|c| c.is_whitespace() || c == '.' || c == ',' || c == ';' || c == '\'' || c == '`')
+        .unwrap_or(false)
+}
+
+// Detection values from the command line override the configured ones
+fn tune(config: &mut AppConfig, tuning: &Tuning) -> Result<()> {
+    tuning.apply(&mut config.detection);
+    config.validate_detection()
+}
+
+fn record(config: &AppConfig, output: &Path, redact: bool) -> Result<()> {
+    let file = File::create(output)
+        .with_context(|| format!("Failed to write {}", output.display()))?;
+    let file = BufWriter::new(file);
+    let mut writer = if redact || config.record_redact {
+        TraceWriter::redacted(file, config.layout.clone())?
+    } else {
+        TraceWriter::new(file)?
+    };
+
+    let mut listener = KeyboardListener::new()?;
+    info!("Recording key events to {}, press Ctrl+C to stop", output.display());
+    listener.listen(|event| {
+        if let Err(e) = writer.write(&event) {
+            error!("Failed to record to {}: {:#}", output.display(), e);
+        }
+    })
+}
+
+fn replay(config: &AppConfig, trace: &Path) -> Result<()> {
+    let library = load_libraries(config)?;
+    let mut chord_stream = ChordStream::with_source(config.detection.clone(), TraceSource::open(trace)?);
+
+    let mut start = None;
+    let (mut chords, mut expanded) = (0, 0);
+    chord_stream.process_events(|event| match event {
+        StreamEvent::Key(KeyEvent { time, .. }) => {
+            start.get_or_insert(time);
+        }
+        StreamEvent::Chord { keys: chord, time } => {
+            let expansion = resolve(&library, &chord);
+            // Single keys are ordinary typing unless they have an entry
+            if chord.len() < 2 && expansion.is_none() {
+                return;
             }
+            chords += 1;
 
-            // Update last_char_was_space based on the last character of the injected text
-            last_char_was_space = text.chars().last()
-                .map(|c| c.is_whitespace() || c == '.' || c == ',' || c == ';' || c == '\'' || c == '`')
-                .unwrap_or(false);
+            let at = time.duration_since(start.unwrap_or(time)).as_secs_f64();
+            let keys = chord.iter().map(|key| format!("{:?}", key)).collect::<Vec<_>>().join("+");
+            match expansion {
+                Some(expansion) => {
+                    expanded += 1;
+                    println!("{:>10.3}s  {} => {:?}", at, keys, expansion.text);
+                }
+                None => println!("{:>10.3}s  {}", at, keys),
+            }
         }
     })?;
 
+    println!("{} chords detected, {} with an entry", chords, expanded);
     Ok(())
 }
+
+fn calibrate(config: &AppConfig) -> Result<()> {
+    let library = load_libraries(config)?;
+    let prompts = calibration_prompts(&library, &config.layout);
+    if prompts.is_empty() {
+        bail!("Calibration needs [chords] entries of several keys typing a word");
+    }
+
+    // The keyboards are read alongside the terminal, which shows what is typed
+    let (sender, events) = mpsc::channel();
+    let mut listener = KeyboardListener::new()?;
+    thread::spawn(move || listener.listen(|event| {
+        let _ = sender.send(event);
+    }));
+
+    let mut samples = Vec::new();
+    println!("Type each word as you normally would, then press Enter.");
+    for (word, _) in &prompts {
+        let prompt = format!("Type `{}`", word);
+        samples.push(prompt_sample(&events, SampleKind::Word, &prompt, |line, _| line == word)?);
+    }
+    println!("Now press the keys of each chord together, release them, then press Enter.");
+    for (_, keys) in &prompts {
+        let label = keys.iter().map(|key| key_label(&config.layout, *key)).collect::<Vec<_>>().join("+");
+        let prompt = format!("Press `{}`", label);
+        samples.push(prompt_sample(&events, SampleKind::Chord, &prompt, |_, sample| sample.keys() == *keys)?);
+    }
+
+    let current = stream::evaluate(&config.detection, &samples);
+    let calibration = stream::calibrate(&config.detection, &samples);
+    let detection = &calibration.config;
+    let path = AppConfig::save_settings(&[
+        ("roll_threshold", detection.roll_threshold.to_string()),
+        ("min_overlap_ratio", detection.min_overlap_ratio.to_string()),
+        ("typing_speed_factor", detection.typing_speed_factor.to_string()),
+    ])?;
+
+    println!();
+    println!("With the previous values: {}", current);
+    println!(
+        "Calibrated roll_threshold = {}, min_overlap_ratio = {}, typing_speed_factor = {}",
+        detection.roll_threshold, detection.min_overlap_ratio, detection.typing_speed_factor,
+    );
+    println!("Expected: {}", calibration.rates);
+    println!("Saved to {}", path.display());
+    Ok(())
+}
+
+// Chords of several keys typing a word, spread evenly over the loaded ones
+fn calibration_prompts(library: &ChordLibrary, layout: &Layout) -> Vec<(String, Vec<KeyCode>)> {
+    let mut chords: Vec<(String, Vec<KeyCode>)> = library.section(Section::Chord).iter()
+        .filter(|(chord, expansion)| {
+            chord.len() > 1
+                && !expansion.text.is_empty()
+                && expansion.text.chars().all(|c| c.is_alphabetic() && layout.keystroke(c).is_some())
+        })
+        .map(|(chord, expansion)| (expansion.text.clone(), chord.keys().collect()))
+        .collect();
+    chords.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let step = chords.len().div_ceil(CALIBRATION_PROMPTS).max(1);
+    chords.into_iter().step_by(step).collect()
+}
+
+// Ask for a sample until one passes `check`, given the line the terminal read.
+// A sample holds the key events from the prompt up to Enter.
+fn prompt_sample(
+    events: &Receiver<KeyEvent>,
+    kind: SampleKind,
+    prompt: &str,
+    check: impl Fn(&str, &Sample) -> bool,
+) -> Result<Sample> {
+    loop {
+        // Forget whatever was typed before the prompt
+        while events.try_recv().is_ok() {}
+        print!("{}: ", prompt);
+        std::io::stdout().flush()?;
+
+        let mut line = String::new();
+        if std::io::stdin().read_line(&mut line)? == 0 {
+            bail!("Calibration cancelled");
+        }
+
+        // The terminal can get the line before the listener gets the Enter key
+        let mut sample = Sample { kind, events: Vec::new() };
+        loop {
+            let event = events.recv_timeout(Duration::from_secs(1))
+                .context("No key events came from the keyboards")?;
+            match event.key {
+                KeyCode::KEY_ENTER | KeyCode::KEY_KPENTER if event.pressed => break,
+                KeyCode::KEY_ENTER | KeyCode::KEY_KPENTER => {}
+                _ => sample.events.push(event),
+            }
+        }
+
+        if check(line.trim_end_matches(['\r', '\n']), &sample) {
+            return Ok(sample);
+        }
+        println!("That didn't match, try again.");
+    }
+}
+
+// The character a key types, or its name if it types none
+fn key_label(layout: &Layout, key: KeyCode) -> String {
+    match layout.character(Keystroke { key, shift: false }) {
+        Some(c) if !c.is_whitespace() => c.to_string(),
+        _ => format!("{:?}", key),
+    }
+}
+
+fn compile(config: &AppConfig, files: Vec<PathBuf>) -> Result<()> {
+    let files = if files.is_empty() { config.library_files()? } else { files };
+    let options = load_options(config, false);
+
+    for file in &files {
+        let (cache, diagnostics) = ChordLibrary::compile(file, &options)?;
+        log_diagnostics(&diagnostics);
+        info!("Compiled {} to {}", file.display(), cache.display());
+    }
+    Ok(())
+}
+
+fn lookup(config: &AppConfig, words: &[String]) -> Result<()> {
+    let library = load_libraries(config)?;
+
+    for word in words {
+        let origins = library.lookup(word);
+        if origins.is_empty() {
+            println!("{}: no chord", word);
+        }
+        for origin in origins {
+            println!("{}: {}", word, origin);
+        }
+    }
+    Ok(())
+}
+
+fn import(config: &AppConfig, conversion: &Conversion) -> Result<()> {
+    let Conversion { format, input, output, .. } = conversion;
+    let options = convert_options(config, conversion);
+    let Some(format) = format.or_else(|| Format::from_path(input)) else {
+        bail!("Can't tell the format of {}, pass --format", input.display());
+    };
+
+    let (library, diagnostics) = convert::import(format, input, &options)?;
+    log_diagnostics(&diagnostics);
+
+    let document = library.to_document()?;
+    match output {
+        Some(output) => {
+            document.save(output)?;
+            info!("Imported {} into {}", input.display(), output.display());
+        }
+        None => print!("{}", document),
+    }
+    Ok(())
+}
+
+fn export(config: &AppConfig, conversion: &Conversion) -> Result<()> {
+    let Conversion { format, input, output, .. } = conversion;
+    let options = convert_options(config, conversion);
+    let Some(format) = format.or_else(|| output.as_deref().and_then(Format::from_path)) else {
+        bail!("Can't tell which format to export to, pass --format");
+    };
+
+    let (library, diagnostics) = ChordLibrary::load_with(input, &load_options(config, false))?;
+    log_diagnostics(&diagnostics);
+
+    let (exported, diagnostics) = convert::export(format, &library, &options)?;
+    log_diagnostics(&diagnostics);
+
+    match output {
+        Some(output) => {
+            std::fs::write(output, exported)
+                .with_context(|| format!("Failed to write {}", output.display()))?;
+            info!("Exported {} to {}", input.display(), output.display());
+        }
+        None => print!("{}", exported),
+    }
+    Ok(())
+}
+
+// The layout given on the command line overrides the configured one
+fn convert_options(config: &AppConfig, conversion: &Conversion) -> ConvertOptions {
+    ConvertOptions {
+        layout: conversion.layout.clone().unwrap_or_else(|| config.layout.clone()),
+        steno: conversion.steno.clone(),
+    }
+}
+
+fn load_options(config: &AppConfig, strict: bool) -> LoadOptions {
+    LoadOptions {
+        strict,
+        duplicates: config.duplicate_policy,
+        cache: config.library_cache,
+        layout: config.layout.clone(),
+    }
+}
+
+fn load_libraries(config: &AppConfig) -> Result<ChordLibrary> {
+    load_reported(config).map(|(library, _)| library)
+}
+
+// Load every library, also returning what was reported
+fn load_reported(config: &AppConfig) -> Result<(ChordLibrary, Diagnostics)> {
+    let options = load_options(config, false);
+    let files = config.library_files()?;
+    if files.is_empty() {
+        warn!("No .zc libraries found in {}", config.library_path.display());
+    }
+
+    let (library, diagnostics) = ChordLibrary::load_layered(&files, &options)?;
+    log_diagnostics(&diagnostics);
+    for file in &files {
+        info!("Loaded library: {}", file.display());
+    }
+    Ok((library, diagnostics))
+}
+
+// Errors in `reported` beyond those already in `accepted`. Lines shift as a
+// file is edited, so errors are told apart by file and message.
+fn added_errors(accepted: &Diagnostics, reported: &Diagnostics) -> usize {
+    let mut known: Vec<&Diagnostic> = accepted.iter().filter(|d| d.severity == Severity::Error).collect();
+    reported.iter()
+        .filter(|d| d.severity == Severity::Error)
+        .filter(|d| match known.iter().position(|k| k.file == d.file && k.message == d.message) {
+            Some(index) => {
+                known.swap_remove(index);
+                false
+            }
+            None => true,
+        })
+        .count()
+}
+
+fn log_diagnostics(diagnostics: &Diagnostics) {
+    for diagnostic in diagnostics {
+        match diagnostic.severity {
+            Severity::Error => error!("{}", diagnostic),
+            Severity::Warning => warn!("{}", diagnostic),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zipchord::stream::{ChordConfig, Timestamp, VecSource};
+
+    // Press the keys of each chord together, a second apart
+    fn chorded(chords: &[&[KeyCode]]) -> VecSource {
+        let at = |ms: u64| Timestamp::from_duration(Duration::from_millis(ms));
+        let mut events = Vec::new();
+        for (i, chord) in chords.iter().enumerate() {
+            let start = 1000 * (i as u64 + 1);
+            for (j, key) in chord.iter().enumerate() {
+                events.push(KeyEvent { key: *key, pressed: true, time: at(start + 5 * j as u64) });
+            }
+            for (j, key) in chord.iter().enumerate() {
+                events.push(KeyEvent { key: *key, pressed: false, time: at(start + 100 + 5 * j as u64) });
+            }
+        }
+        VecSource::new(events)
+    }
+
+    fn edits(library: &ChordLibrary, source: VecSource) -> Vec<Edit> {
+        let mut typing = Typing::new(Duration::from_secs(1), Layout::default());
+        let mut edits = Vec::new();
+        ChordStream::with_source(ChordConfig::default(), source)
+            .process_events(|event| edits.extend(typing.handle(library, event)))
+            .unwrap();
+        edits
+    }
+
+    #[test]
+    fn chord_after_a_prefix_completes_the_word() {
+        let library = ChordLibrary::builder()
+            .entry(Section::Prefix, "u+n", "un").unwrap()
+            .chord("d+o", "do").unwrap()
+            .build();
+
+        let source = chorded(&[&[KeyCode::KEY_U, KeyCode::KEY_N], &[KeyCode::KEY_D, KeyCode::KEY_O]]);
+        assert_eq!(edits(&library, source), [
+            Edit { erase: 2, text: "un".to_string() },
+            Edit { erase: 2, text: "do ".to_string() },
+        ]);
+    }
+}
@@ -0,0 +1,273 @@
+//! Choosing detection values from labelled typing.
+//!
+//! Calibration takes words typed normally, which must never be taken for a
+//! chord, and chords pressed on purpose, which must be. The samples are
+//! played in the order they were recorded through one [`TimingAnalyzer`], as
+//! [`ChordStream`](super::ChordStream) plays live typing, so the typing speed
+//! learned from earlier samples carries over to later ones. The keys held
+//! within each sample are grouped as `ChordStream` groups them and scored
+//! with [`TimingAnalyzer::calculate_roll_score`]. The roll
+//! threshold, minimum overlap ratio and typing speed factor are then searched
+//! for the values separating the two kinds best; the chord window is kept.
+
+use evdev::KeyCode;
+use std::fmt;
+
+use super::detect::{ChordConfig, MAX_CHORD_SIZE};
+use super::source::KeyEvent;
+use super::timing::{KeyTiming, TimingAnalyzer};
+
+// Steps of the search, from 0 to 1
+const OVERLAP_STEPS: u16 = 10;
+const SPEED_STEPS: u16 = 10;
+const THRESHOLD_STEPS: u16 = 20;
+
+/// What the user was asked to do while a sample was recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleKind {
+    /// A word typed normally
+    Word,
+    /// Every key of a chord pressed together
+    Chord,
+}
+
+/// The key events of one prompt, without the key confirming it
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub kind: SampleKind,
+    pub events: Vec<KeyEvent>,
+}
+
+impl Sample {
+    /// Every key pressed in the sample, once each
+    pub fn keys(&self) -> Vec<KeyCode> {
+        let mut keys: Vec<KeyCode> = self.events.iter().filter(|event| event.pressed).map(|event| event.key).collect();
+        keys.sort_by_key(|key| key.code());
+        keys.dedup();
+        keys
+    }
+}
+
+/// How often detection values get the samples wrong
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ErrorRates {
+    /// Words in which a chord was detected
+    pub false_positives: usize,
+    pub words: usize,
+    /// Chords not detected as pressed
+    pub false_negatives: usize,
+    pub chords: usize,
+}
+
+impl ErrorRates {
+    pub fn false_positive_rate(&self) -> f32 {
+        rate(self.false_positives, self.words)
+    }
+
+    pub fn false_negative_rate(&self) -> f32 {
+        rate(self.false_negatives, self.chords)
+    }
+
+    // Both kinds of error weigh the same, however many samples each has
+    fn total(&self) -> f32 {
+        self.false_positive_rate() + self.false_negative_rate()
+    }
+}
+
+impl fmt::Display for ErrorRates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.0}% false positives ({} of {} words taken for a chord), {:.0}% false negatives ({} of {} chords missed)",
+            self.false_positive_rate() * 100.0, self.false_positives, self.words,
+            self.false_negative_rate() * 100.0, self.false_negatives, self.chords,
+        )
+    }
+}
+
+/// Detection values found by [`calibrate`] and how they do on the samples
+#[derive(Debug, Clone)]
+pub struct Calibration {
+    pub config: ChordConfig,
+    pub rates: ErrorRates,
+}
+
+/// How `config` does on the samples
+pub fn evaluate(config: &ChordConfig, samples: &[Sample]) -> ErrorRates {
+    let scores = score_samples(config, samples);
+    count_errors(&scores, config.roll_threshold)
+}
+
+/// Search the values of `base` other than the chord window for those making
+/// the fewest errors on the samples.
+///
+/// Of equally good values, those leaving the widest margin between the roll
+/// threshold and the nearest sample score win.
+pub fn calibrate(base: &ChordConfig, samples: &[Sample]) -> Calibration {
+    // Errors, margin and values of the best candidate so far
+    let mut best = (f32::INFINITY, f32::NEG_INFINITY, Calibration { config: base.clone(), rates: ErrorRates::default() });
+
+    for overlap in 0..=OVERLAP_STEPS {
+        for speed in 0..=SPEED_STEPS {
+            let mut config = ChordConfig {
+                min_overlap_ratio: f32::from(overlap) / f32::from(OVERLAP_STEPS),
+                typing_speed_factor: f32::from(speed) / f32::from(SPEED_STEPS),
+                ..base.clone()
+            };
+            let scores = score_samples(&config, samples);
+
+            // A score of 1 is never below the threshold, so 0 is left out
+            for threshold in 1..=THRESHOLD_STEPS {
+                config.roll_threshold = f32::from(threshold) / f32::from(THRESHOLD_STEPS);
+                let rates = count_errors(&scores, config.roll_threshold);
+                let errors = rates.total();
+                let margin = scores.iter()
+                    .filter_map(|(_, score)| *score)
+                    .map(|score| (score - config.roll_threshold).abs())
+                    .fold(f32::INFINITY, f32::min);
+
+                if errors < best.0 || (errors == best.0 && margin > best.1) {
+                    best = (errors, margin, Calibration { config: config.clone(), rates });
+                }
+            }
+        }
+    }
+
+    best.2
+}
+
+// The lowest roll score of each word, and the score of each chord pressed
+// with all its keys held. `None` if no keys were scored together.
+fn score_samples(config: &ChordConfig, samples: &[Sample]) -> Vec<(SampleKind, Option<f32>)> {
+    let mut analyzer = TimingAnalyzer::new(
+        config.base_chord_window,
+        config.roll_threshold,
+        config.typing_speed_factor,
+        config.min_overlap_ratio,
+    );
+    samples.iter()
+        .map(|sample| {
+            let groups = roll_scores(&mut analyzer, &sample.events);
+            let score = match sample.kind {
+                SampleKind::Word => groups.iter().map(|(_, score)| *score).reduce(f32::min),
+                SampleKind::Chord => {
+                    let pressed = sample.keys();
+                    groups.iter()
+                        .find(|(keys, _)| pressed.iter().all(|key| keys.contains(key)))
+                        .map(|(_, score)| *score)
+                }
+            };
+            (sample.kind, score)
+        })
+        .collect()
+}
+
+fn count_errors(scores: &[(SampleKind, Option<f32>)], roll_threshold: f32) -> ErrorRates {
+    let mut rates = ErrorRates::default();
+    for (kind, score) in scores {
+        let chord = score.is_some_and(|score| score < roll_threshold);
+        match kind {
+            SampleKind::Word => {
+                rates.words += 1;
+                rates.false_positives += usize::from(chord);
+            }
+            SampleKind::Chord => {
+                rates.chords += 1;
+                rates.false_negatives += usize::from(!chord);
+            }
+        }
+    }
+    rates
+}
+
+// The keys held together each time one of several is released, with their
+// roll score, grouped as `ChordStream` does. Keys still held when the sample
+// ends were released after it, so they aren't carried to the next one.
+fn roll_scores(analyzer: &mut TimingAnalyzer, events: &[KeyEvent]) -> Vec<(Vec<KeyCode>, f32)> {
+    let mut active: Vec<(KeyCode, KeyTiming)> = Vec::with_capacity(MAX_CHORD_SIZE);
+    let mut groups = Vec::new();
+
+    for event in events {
+        if event.pressed {
+            if let Some((_, last)) = active.last() {
+                analyzer.update_typing_speed(event.time.duration_since(last.press_time), event.time);
+            }
+            let window = analyzer.get_adjusted_chord_window();
+            if active.first().is_some_and(|(_, oldest)| event.time.duration_since(oldest.press_time) > window) {
+                active.clear();
+            }
+            if active.len() < MAX_CHORD_SIZE && !active.iter().any(|(key, _)| *key == event.key) {
+                active.push((event.key, KeyTiming { press_time: event.time, release_time: None }));
+            }
+        } else if let Some(pos) = active.iter().position(|(key, _)| *key == event.key) {
+            active[pos].1.release_time = Some(event.time);
+            if active.len() > 1 {
+                let timings: Vec<KeyTiming> = active.iter().map(|(_, timing)| timing.clone()).collect();
+                let keys = active.iter().map(|(key, _)| *key).collect();
+                groups.push((keys, analyzer.calculate_roll_score(&timings)));
+            }
+            active.remove(pos);
+        }
+    }
+    groups
+}
+
+fn rate(count: usize, total: usize) -> f32 {
+    if total == 0 { 0.0 } else { count as f32 / total as f32 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::timing::Timestamp;
+    use std::time::Duration;
+
+    // Press and release times of each key in milliseconds, from `start`
+    fn sample(kind: SampleKind, start: u64, keys: &[(KeyCode, u64, u64)]) -> Sample {
+        let at = |millis: u64| Timestamp::from_duration(Duration::from_millis(start + millis));
+        let mut events: Vec<KeyEvent> = keys.iter()
+            .flat_map(|&(key, press, release)| [
+                KeyEvent { key, pressed: true, time: at(press) },
+                KeyEvent { key, pressed: false, time: at(release) },
+            ])
+            .collect();
+        events.sort_by_key(|event| event.time);
+        Sample { kind, events }
+    }
+
+    // Words rolled with a little overlap, then chords pressed together,
+    // recorded a few seconds apart as the prompts come
+    fn samples() -> Vec<Sample> {
+        let (t, h, e, a) = (KeyCode::KEY_T, KeyCode::KEY_H, KeyCode::KEY_E, KeyCode::KEY_A);
+        vec![
+            sample(SampleKind::Word, 10_000, &[(t, 0, 90), (h, 60, 150), (e, 120, 200)]),
+            sample(SampleKind::Word, 13_000, &[(t, 0, 100), (h, 70, 160), (a, 140, 210), (t, 200, 260)]),
+            sample(SampleKind::Word, 16_000, &[(t, 0, 80), (h, 55, 140), (e, 110, 180)]),
+            sample(SampleKind::Chord, 19_000, &[(t, 0, 100), (h, 5, 105)]),
+            sample(SampleKind::Chord, 22_000, &[(t, 8, 120), (h, 0, 110), (e, 12, 115)]),
+            sample(SampleKind::Chord, 25_000, &[(a, 0, 95), (t, 10, 100)]),
+        ]
+    }
+
+    #[test]
+    fn calibrated_values_separate_rolls_from_chords() {
+        let samples = samples();
+        let calibration = calibrate(&ChordConfig::default(), &samples);
+        assert_eq!(calibration.rates, ErrorRates { false_positives: 0, words: 3, false_negatives: 0, chords: 3 });
+        assert_eq!(evaluate(&calibration.config, &samples), calibration.rates);
+        assert_eq!(calibration.config.base_chord_window, ChordConfig::default().base_chord_window);
+
+        let threshold = calibration.config.roll_threshold;
+        for (kind, score) in score_samples(&calibration.config, &samples) {
+            let score = score.unwrap();
+            match kind {
+                SampleKind::Word => assert!(score >= threshold, "word scored {} under {}", score, threshold),
+                SampleKind::Chord => assert!(score < threshold, "chord scored {} over {}", score, threshold),
+            }
+        }
+
+        // A threshold nothing scores under misses every chord
+        let strict = ChordConfig { roll_threshold: 0.0, ..calibration.config };
+        assert_eq!(evaluate(&strict, &samples).false_negatives, 3);
+    }
+}
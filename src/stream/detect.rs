@@ -4,10 +4,12 @@ use log;
 use std::time::{Duration, Instant};
 
 use super::listener::KeyboardListener;
-use super::timing::{KeyTiming, TimingAnalyzer};
+use super::source::{EventSource, KeyEvent};
+use super::timing::{KeyTiming, TimingAnalyzer, Timestamp};
 
-const MAX_CHORD_SIZE: usize = 8; // Maximum reasonable number of keys in a chord
+pub(super) const MAX_CHORD_SIZE: usize = 8; // Maximum reasonable number of keys in a chord
 
+#[derive(Debug, Clone)]
 pub struct ChordConfig {
     pub base_chord_window: Duration,
     pub roll_threshold: f32,
@@ -26,45 +28,67 @@ impl Default for ChordConfig {
     }
 }
 
+/// What [`ChordStream::process_events`] reports
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamEvent {
+    /// Every key press and release, before chord detection
+    Key(KeyEvent),
+    /// A single key or a chord was released
+    Chord {
+        keys: Vec<KeyCode>,
+        /// When the release completing it happened
+        time: Timestamp,
+    },
+}
+
 #[derive(Debug)]
 struct ActiveKey {
     code: KeyCode,
     timing: KeyTiming,
 }
 
-pub struct ChordStream {
+/// Detects chords in the key events of a source, by default every keyboard
+pub struct ChordStream<S = KeyboardListener> {
     active_keys: Vec<ActiveKey>,
     timing_buffer: Vec<KeyTiming>,
     chord_buffer: Vec<KeyCode>,
-    last_activity: Instant,
+    last_activity: Timestamp,
     timing_analyzer: TimingAnalyzer,
-    listener: KeyboardListener,
+    source: S,
 }
 
-impl ChordStream {
+impl ChordStream<KeyboardListener> {
+    /// Detect chords typed on the keyboards of this machine
     pub fn new(config: ChordConfig) -> Result<Self> {
-        Ok(Self {
+        Ok(Self::with_source(config, KeyboardListener::new()?))
+    }
+
+    pub fn with_default_config() -> Result<Self> {
+        Self::new(ChordConfig::default())
+    }
+}
+
+impl<S: EventSource> ChordStream<S> {
+    pub fn with_source(config: ChordConfig, source: S) -> Self {
+        Self {
             active_keys: Vec::with_capacity(MAX_CHORD_SIZE),
             timing_buffer: Vec::with_capacity(MAX_CHORD_SIZE),
             chord_buffer: Vec::with_capacity(MAX_CHORD_SIZE),
-            last_activity: Instant::now(),
+            last_activity: Timestamp::default(),
             timing_analyzer: TimingAnalyzer::new(
                 config.base_chord_window,
                 config.roll_threshold,
                 config.typing_speed_factor,
                 config.min_overlap_ratio,
             ),
-            listener: KeyboardListener::new()?,
-        })
-    }
-
-    pub fn with_default_config() -> Result<Self> {
-        Self::new(ChordConfig::default())
+            source,
+        }
     }
 
+    /// Report the events of the source until it runs out or fails
     pub fn process_events<F>(&mut self, mut callback: F) -> Result<()>
     where
-        F: FnMut(Vec<KeyCode>) + 'static,
+        F: FnMut(StreamEvent),
     {
         let timing_analyzer = &mut self.timing_analyzer;
         let active_keys = &mut self.active_keys;
@@ -72,15 +96,17 @@ impl ChordStream {
         let chord_buffer = &mut self.chord_buffer;
         let last_activity = &mut self.last_activity;
 
-        let chord_callback = move |key: KeyCode, is_press: bool| {
+        // `now` is when the kernel saw the event, not when it is handled
+        let chord_callback = move |event: KeyEvent| {
+            let KeyEvent { key, pressed: is_press, time: now } = event;
             let event_start = Instant::now();
-            let now = Instant::now();
+            callback(StreamEvent::Key(event));
 
             if is_press {
                 // Update timing metrics if we have a previous key press
                 if let Some(last_key) = active_keys.last() {
                     let interval = now.duration_since(last_key.timing.press_time);
-                    timing_analyzer.update_typing_speed(interval);
+                    timing_analyzer.update_typing_speed(interval, now);
                 }
 
                 // Create new key timing
@@ -114,13 +140,14 @@ impl ChordStream {
                 if let Some(pos) = active_keys.iter().position(|k| k.code == key) {
                     active_keys[pos].timing.release_time = Some(now);
 
+                    let mut chorded = false;
                     match active_keys.len().cmp(&1) {
                         std::cmp::Ordering::Less => {}
                         // Process single key releases immediately
                         std::cmp::Ordering::Equal => {
                             chord_buffer.clear();
                             chord_buffer.push(key);
-                            callback(chord_buffer.clone());
+                            callback(StreamEvent::Chord { keys: chord_buffer.clone(), time: now });
                             let event_duration = event_start.elapsed();
                             log::debug!("Single key processing took: {:?}", event_duration);
                         }
@@ -134,13 +161,14 @@ impl ChordStream {
 
                             // If this forms a valid chord, trigger callback
                             if timing_analyzer.is_chord(timing_buffer) {
+                                chorded = true;
                                 let chord_start = Instant::now();
                                 chord_buffer.clear();
                                 chord_buffer.extend(active_keys.iter().map(|k| k.code));
 
                                 log::debug!("Detected chord: {:?}", chord_buffer);
                                 if !chord_buffer.is_empty() {
-                                    callback(chord_buffer.clone());
+                                    callback(StreamEvent::Chord { keys: chord_buffer.clone(), time: now });
                                     let chord_duration = chord_start.elapsed();
                                     let detection_duration = chord_detection_start.elapsed();
                                     let total_duration = event_start.elapsed();
@@ -157,8 +185,12 @@ impl ChordStream {
                         }
                     }
 
-                    // Remove the released key
-                    active_keys.remove(pos);
+                    // The keys of a chord are used up, releasing the others reports nothing more
+                    if chorded {
+                        active_keys.clear();
+                    } else {
+                        active_keys.remove(pos);
+                    }
                 }
 
                 *last_activity = now;
@@ -168,6 +200,90 @@ impl ChordStream {
             }
         };
 
-        self.listener.listen(chord_callback)
+        self.source.listen(chord_callback)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::VecSource;
+
+    fn press(key: KeyCode, ms: u64) -> KeyEvent {
+        KeyEvent { key, pressed: true, time: Timestamp::from_duration(Duration::from_millis(ms)) }
+    }
+
+    fn release(key: KeyCode, ms: u64) -> KeyEvent {
+        KeyEvent { key, pressed: false, time: Timestamp::from_duration(Duration::from_millis(ms)) }
+    }
+
+    // Every event the stream reports for `events`
+    fn detect(events: Vec<KeyEvent>) -> Vec<StreamEvent> {
+        let mut stream = ChordStream::with_source(ChordConfig::default(), VecSource::new(events));
+        let mut reported = Vec::new();
+        stream.process_events(|event| reported.push(event)).unwrap();
+        reported
+    }
+
+    fn chords(reported: &[StreamEvent]) -> Vec<Vec<KeyCode>> {
+        reported.iter()
+            .filter_map(|event| match event {
+                StreamEvent::Chord { keys, .. } => Some(keys.clone()),
+                StreamEvent::Key(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keys_pressed_together_are_a_chord() {
+        let reported = detect(vec![
+            press(KeyCode::KEY_T, 1000),
+            press(KeyCode::KEY_H, 1005),
+            release(KeyCode::KEY_T, 1100),
+            release(KeyCode::KEY_H, 1105),
+        ]);
+
+        assert_eq!(reported[3], StreamEvent::Chord {
+            keys: vec![KeyCode::KEY_T, KeyCode::KEY_H],
+            time: Timestamp::from_duration(Duration::from_millis(1100)),
+        });
+        // Releasing the rest of the chord types nothing
+        assert_eq!(chords(&reported), [vec![KeyCode::KEY_T, KeyCode::KEY_H]]);
+    }
+
+    #[test]
+    fn rolled_keys_are_not_a_chord() {
+        let reported = detect(vec![
+            press(KeyCode::KEY_T, 1000),
+            press(KeyCode::KEY_H, 1100),
+            release(KeyCode::KEY_T, 1110),
+            release(KeyCode::KEY_H, 1200),
+        ]);
+
+        assert_eq!(chords(&reported), [vec![KeyCode::KEY_H]]);
+    }
+
+    #[test]
+    fn single_key_release_is_reported() {
+        let events = vec![press(KeyCode::KEY_A, 1000), release(KeyCode::KEY_A, 1050)];
+        let reported = detect(events.clone());
+
+        assert_eq!(reported, [
+            StreamEvent::Key(events[0]),
+            StreamEvent::Key(events[1]),
+            StreamEvent::Chord { keys: vec![KeyCode::KEY_A], time: events[1].time },
+        ]);
+    }
+
+    #[test]
+    fn press_after_the_window_starts_over() {
+        let reported = detect(vec![
+            press(KeyCode::KEY_T, 1000),
+            press(KeyCode::KEY_H, 1500),
+            release(KeyCode::KEY_T, 1510),
+            release(KeyCode::KEY_H, 1520),
+        ]);
+
+        assert_eq!(chords(&reported), [vec![KeyCode::KEY_H]]);
     }
 }
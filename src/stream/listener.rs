@@ -1,11 +1,18 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use evdev::{Device, EventSummary, KeyCode};
-use log::error;
+use log::{error, warn};
 use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout};
 use std::os::{
     fd::RawFd,
     unix::io::{AsFd, AsRawFd},
 };
+use std::time::SystemTime;
+
+use super::source::{EventSource, KeyEvent};
+use super::timing::Timestamp;
+
+// EVIOCSCLOCKID, choosing the clock a device stamps its events with
+nix::ioctl_write_ptr!(set_clock_id, b'E', 0xa0, nix::libc::c_int);
 
 pub struct KeyboardListener {
     devices: Vec<Device>,
@@ -13,7 +20,7 @@ pub struct KeyboardListener {
 
 impl KeyboardListener {
     pub fn new() -> Result<Self> {
-        let devices = Self::find_keyboards()?;
+        let devices = Self::find_keyboards();
         if devices.is_empty() {
             bail!("No keyboard devices found");
         }
@@ -21,21 +28,36 @@ impl KeyboardListener {
         Ok(Self { devices })
     }
 
-    fn find_keyboards() -> Result<Vec<Device>> {
+    fn find_keyboards() -> Vec<Device> {
         let mut keyboards = Vec::new();
 
         for (path, device) in evdev::enumerate() {
-            if Self::is_keyboard(&device) {
-                println!(
-                    "Using keyboard: {} ({})",
-                    device.name().unwrap_or("Unknown"),
-                    path.display()
-                );
-                keyboards.push(device);
+            if !Self::is_keyboard(&device) {
+                continue;
             }
+            // A keyboard left on the wall clock would break timing against the others
+            if let Err(e) = Self::use_monotonic_clock(&device) {
+                warn!("Skipping keyboard {}: {:#}", path.display(), e);
+                continue;
+            }
+            println!(
+                "Using keyboard: {} ({})",
+                device.name().unwrap_or("Unknown"),
+                path.display()
+            );
+            keyboards.push(device);
         }
 
-        Ok(keyboards)
+        keyboards
+    }
+
+    // Devices stamp events with the wall clock unless told otherwise
+    fn use_monotonic_clock(device: &Device) -> Result<()> {
+        let clock = nix::libc::CLOCK_MONOTONIC;
+        // SAFETY: the descriptor belongs to `device`, and the kernel only reads `clock`
+        unsafe { set_clock_id(device.as_raw_fd(), &clock) }
+            .with_context(|| format!("Failed to set the clock of {}", device.name().unwrap_or("Unknown")))?;
+        Ok(())
     }
 
     fn is_keyboard(device: &Device) -> bool {
@@ -47,9 +69,14 @@ impl KeyboardListener {
             })
     }
 
-    pub fn listen<F>(&mut self, mut callback: F) -> Result<()>
+}
+
+impl EventSource for KeyboardListener {
+    /// Listen to every keyboard until reading fails; events carry the kernel's
+    /// monotonic timestamp
+    fn listen<F>(&mut self, mut callback: F) -> Result<()>
     where
-        F: FnMut(KeyCode, bool), // Callback receives (key, is_press)
+        F: FnMut(KeyEvent),
     {
         let epoll = Epoll::new(EpollCreateFlags::empty())?;
 
@@ -98,10 +125,13 @@ impl KeyboardListener {
 
                 // Process the fetched events
                 for event in events {
+                    // evdev hands over the monotonic timeval as if counted from the epoch
+                    let since_boot = event.timestamp().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+                    let time = Timestamp::from_duration(since_boot);
                     if let EventSummary::Key(_, key, value) = event.destructure() {
                         match value {
-                            1 => callback(key, true),  // Key press
-                            0 => callback(key, false), // Key release
+                            1 => callback(KeyEvent { key, pressed: true, time }),  // Key press
+                            0 => callback(KeyEvent { key, pressed: false, time }), // Key release
                             _ => continue,
                         }
                     }
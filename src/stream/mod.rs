@@ -1,6 +1,13 @@
+mod calibrate;
 mod detect;
 mod listener;
+mod source;
 mod timing;
+mod trace;
 
-pub use detect::{ChordStream, ChordConfig};
+pub use calibrate::{calibrate, evaluate, Calibration, ErrorRates, Sample, SampleKind};
+pub use detect::{ChordStream, ChordConfig, StreamEvent};
 pub use listener::KeyboardListener;
+pub use source::{EventSource, KeyEvent, VecSource};
+pub use timing::Timestamp;
+pub use trace::{TraceSource, TraceWriter};
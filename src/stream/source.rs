@@ -0,0 +1,51 @@
+use anyhow::Result;
+use evdev::KeyCode;
+
+use super::timing::Timestamp;
+
+/// A key press or release and when it happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: KeyCode,
+    pub pressed: bool,
+    pub time: Timestamp,
+}
+
+/// Where [`ChordStream`](super::ChordStream) reads key events from
+pub trait EventSource {
+    /// Call `callback` with every event in order, until the source runs out
+    /// or fails. Sources backed by a device never run out.
+    fn listen<F>(&mut self, callback: F) -> Result<()>
+    where
+        F: FnMut(KeyEvent);
+}
+
+/// Events held in memory, played back once
+#[derive(Debug, Clone, Default)]
+pub struct VecSource {
+    events: Vec<KeyEvent>,
+}
+
+impl VecSource {
+    pub fn new(events: Vec<KeyEvent>) -> Self {
+        Self { events }
+    }
+}
+
+impl From<Vec<KeyEvent>> for VecSource {
+    fn from(events: Vec<KeyEvent>) -> Self {
+        Self::new(events)
+    }
+}
+
+impl EventSource for VecSource {
+    fn listen<F>(&mut self, mut callback: F) -> Result<()>
+    where
+        F: FnMut(KeyEvent),
+    {
+        for event in self.events.drain(..) {
+            callback(event);
+        }
+        Ok(())
+    }
+}
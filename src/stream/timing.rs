@@ -1,9 +1,40 @@
-use std::time::{Duration, Instant};
+use nix::time::{clock_gettime, ClockId};
+use std::time::Duration;
+
+/// When the kernel saw a key event, on the monotonic clock.
+///
+/// Events carry this from the moment the device reported them, so delays
+/// in waking up and reading them don't skew the timings. Unlike the wall
+/// clock, the monotonic clock never jumps when the system time is set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(Duration);
+
+impl Timestamp {
+    /// The current time on the clock key events are stamped with
+    pub fn now() -> Self {
+        let now = clock_gettime(ClockId::CLOCK_MONOTONIC).expect("the monotonic clock is always available");
+        Self(Duration::from(now))
+    }
+
+    /// A time on the monotonic clock, counted from its start at boot
+    pub fn from_duration(since_boot: Duration) -> Self {
+        Self(since_boot)
+    }
+
+    pub fn as_duration(self) -> Duration {
+        self.0
+    }
+
+    /// Time from `earlier` to this timestamp, zero if the clock went backwards
+    pub fn duration_since(self, earlier: Timestamp) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct KeyTiming {
-    pub press_time: Instant,
-    pub release_time: Option<Instant>,
+    pub press_time: Timestamp,
+    pub release_time: Option<Timestamp>,
 }
 
 #[derive(Debug, Clone)]
@@ -19,7 +50,7 @@ pub struct TimingAnalyzer {
     recent_press_intervals: Vec<Duration>,
     average_typing_speed: Duration,
     cached_chord_window: Duration,
-    last_speed_update: Instant,
+    last_speed_update: Timestamp,
     speed_cache_duration: Duration,
 }
 
@@ -39,7 +70,7 @@ impl TimingAnalyzer {
             recent_press_intervals: Vec::with_capacity(10),
             average_typing_speed: base_chord_window,
             cached_chord_window: base_chord_window,
-            last_speed_update: Instant::now(),
+            last_speed_update: Timestamp::default(),
             speed_cache_duration: Duration::from_millis(100), // Update cache every 100ms
         }
     }
@@ -108,8 +139,8 @@ impl TimingAnalyzer {
         self.adjust_for_typing_speed(base_score)
     }
 
-    /// Update typing speed metrics with a new interval
-    pub fn update_typing_speed(&mut self, interval: Duration) {
+    /// Update typing speed metrics with a new interval, ending at `now`
+    pub fn update_typing_speed(&mut self, interval: Duration, now: Timestamp) {
         const MAX_SAMPLES: usize = 10;
 
         self.recent_press_intervals.push(interval);
@@ -117,7 +148,6 @@ impl TimingAnalyzer {
             self.recent_press_intervals.remove(0);
         }
 
-        let now = Instant::now();
         if now.duration_since(self.last_speed_update) >= self.speed_cache_duration {
             // Update average typing speed
             if !self.recent_press_intervals.is_empty() {
@@ -0,0 +1,306 @@
+//! Keystroke trace files.
+//!
+//! A trace is a text file with one key event per line: the kernel
+//! timestamp in seconds on the monotonic clock, `press` or `release`, and
+//! the evdev key name or code. Lines starting with `#` are comments.
+//!
+//! Redacted traces keep the timing of every event, but each key typing a
+//! character other than space is written as one of the placeholder keys
+//! `KEY_F13` to `KEY_F24`, taken in press order from those not held down.
+//! Chord and roll timings survive; the text typed doesn't.
+//!
+//! ```text
+//! # zipchord trace v1
+//! 5321.123456 press KEY_T
+//! 5321.131002 press KEY_H
+//! 5321.190511 release KEY_T
+//! ```
+
+use anyhow::{anyhow, bail, Context, Result};
+use evdev::KeyCode;
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use super::source::{EventSource, KeyEvent};
+use super::timing::Timestamp;
+use crate::library::{is_known_key, Keystroke, Layout};
+
+const HEADER: &str = "# zipchord trace v1";
+
+/// Keys standing in for character keys in redacted traces
+const PLACEHOLDERS: [KeyCode; 12] = [
+    KeyCode::KEY_F13, KeyCode::KEY_F14, KeyCode::KEY_F15, KeyCode::KEY_F16,
+    KeyCode::KEY_F17, KeyCode::KEY_F18, KeyCode::KEY_F19, KeyCode::KEY_F20,
+    KeyCode::KEY_F21, KeyCode::KEY_F22, KeyCode::KEY_F23, KeyCode::KEY_F24,
+];
+
+/// Events read from a trace file as they are played back
+pub struct TraceSource {
+    path: PathBuf,
+    reader: BufReader<File>,
+}
+
+impl TraceSource {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            reader: BufReader::new(file),
+        })
+    }
+}
+
+impl EventSource for TraceSource {
+    fn listen<F>(&mut self, mut callback: F) -> Result<()>
+    where
+        F: FnMut(KeyEvent),
+    {
+        let mut line = String::new();
+        let mut number = 0;
+        loop {
+            line.clear();
+            number += 1;
+            let read = self.reader.read_line(&mut line)
+                .with_context(|| format!("Failed to read {}", self.path.display()))?;
+            if read == 0 {
+                return Ok(());
+            }
+
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            let event = parse_event(trimmed)
+                .with_context(|| format!("{}:{}: invalid trace event `{}`", self.path.display(), number, trimmed))?;
+            callback(event);
+        }
+    }
+}
+
+/// Writes key events as a trace, one line per event
+pub struct TraceWriter<W: Write> {
+    out: W,
+    // Present when character keys are redacted
+    redaction: Option<Redaction>,
+}
+
+struct Redaction {
+    layout: Layout,
+    // The real key held down on each placeholder
+    held: [Option<KeyCode>; PLACEHOLDERS.len()],
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(out: W) -> Result<Self> {
+        Self::start(out, None)
+    }
+
+    /// A writer hiding the keys that type characters on `layout`
+    pub fn redacted(out: W, layout: Layout) -> Result<Self> {
+        Self::start(out, Some(Redaction { layout, held: [None; PLACEHOLDERS.len()] }))
+    }
+
+    fn start(mut out: W, redaction: Option<Redaction>) -> Result<Self> {
+        writeln!(out, "{}", HEADER)?;
+        if redaction.is_some() {
+            writeln!(out, "# character keys are redacted")?;
+        }
+        Ok(Self { out, redaction })
+    }
+
+    /// Write an event and flush it, so an interrupted recording loses nothing
+    pub fn write(&mut self, event: &KeyEvent) -> Result<()> {
+        let key = match &mut self.redaction {
+            Some(redaction) => redaction.redact(event),
+            None => event.key,
+        };
+        let time = event.time.as_duration();
+        let action = if event.pressed { "press" } else { "release" };
+        if is_known_key(key) {
+            writeln!(self.out, "{}.{:06} {} {:?}", time.as_secs(), time.subsec_micros(), action, key)?;
+        } else {
+            writeln!(self.out, "{}.{:06} {} {}", time.as_secs(), time.subsec_micros(), action, key.code())?;
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+impl Redaction {
+    fn redact(&mut self, event: &KeyEvent) -> KeyCode {
+        let types_character = self.layout.character(Keystroke { key: event.key, shift: false })
+            .is_some_and(|c| c != ' ');
+        if !types_character {
+            return event.key;
+        }
+
+        let slot = match self.held.iter().position(|held| *held == Some(event.key)) {
+            Some(slot) => slot,
+            // More keys held than placeholders; share the last one
+            None => self.held.iter().position(Option::is_none).unwrap_or(PLACEHOLDERS.len() - 1),
+        };
+        self.held[slot] = event.pressed.then_some(event.key);
+        PLACEHOLDERS[slot]
+    }
+}
+
+fn parse_event(line: &str) -> Result<KeyEvent> {
+    let mut fields = line.split_whitespace();
+    let (Some(time), Some(action), Some(key), None) = (fields.next(), fields.next(), fields.next(), fields.next()) else {
+        bail!("expected `SECONDS press|release KEY`");
+    };
+
+    let time = parse_seconds(time).context("bad timestamp")?;
+    let pressed = match action {
+        "press" => true,
+        "release" => false,
+        other => bail!("unknown action `{}`", other),
+    };
+    let key = match key.parse::<u16>() {
+        Ok(code) => KeyCode::new(code),
+        Err(_) => KeyCode::from_str(key).map_err(|_| anyhow!("unknown key `{}`", key))?,
+    };
+
+    Ok(KeyEvent { key, pressed, time: Timestamp::from_duration(time) })
+}
+
+// `5321.123456` exactly, without a round trip through floats
+fn parse_seconds(text: &str) -> Option<Duration> {
+    let (seconds, fraction) = text.split_once('.').unwrap_or((text, ""));
+    if fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let nanos = if fraction.is_empty() { 0 } else { format!("{:0<9}", fraction).parse().ok()? };
+    Some(Duration::new(seconds.parse().ok()?, nanos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn event(key: KeyCode, pressed: bool, micros: u64) -> KeyEvent {
+        KeyEvent { key, pressed, time: Timestamp::from_duration(Duration::from_micros(micros)) }
+    }
+
+    fn read(path: &Path) -> Result<Vec<KeyEvent>> {
+        let mut events = Vec::new();
+        TraceSource::open(path)?.listen(|event| events.push(event))?;
+        Ok(events)
+    }
+
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("zipchord-trace-test-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn written_traces_read_back() {
+        let events = [
+            event(KeyCode::KEY_T, true, 5_321_123_456),
+            event(KeyCode::KEY_H, true, 5_321_131_002),
+            event(KeyCode::KEY_T, false, 5_321_190_511),
+            event(KeyCode::KEY_H, false, 5_322_000_000),
+            // Codes without an evdev name are written as numbers
+            event(KeyCode::new(0x2ff), true, 5_322_000_001),
+        ];
+        let path = temp_file("round-trip");
+        let mut writer = TraceWriter::new(File::create(&path).unwrap()).unwrap();
+        for event in &events {
+            writer.write(event).unwrap();
+        }
+        drop(writer);
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with("# zipchord trace v1\n5321.123456 press KEY_T\n"), "{}", content);
+        assert!(content.ends_with("5322.000001 press 767\n"), "{}", content);
+        assert_eq!(read(&path).unwrap(), events);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn seconds_are_parsed_exactly() {
+        assert_eq!(parse_seconds("5321.123456"), Some(Duration::new(5321, 123_456_000)));
+        assert_eq!(parse_seconds("0.000000001"), Some(Duration::new(0, 1)));
+        assert_eq!(parse_seconds("12.5"), Some(Duration::from_millis(12_500)));
+        assert_eq!(parse_seconds("12"), Some(Duration::from_secs(12)));
+        for bad in ["", ".5", "-1.5", "1.-5", "1.5e3", "1.0000000001", "one"] {
+            assert_eq!(parse_seconds(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn malformed_lines_are_reported_with_their_line() {
+        assert_eq!(parse_event("1.5 press 30").unwrap(), event(KeyCode::KEY_A, true, 1_500_000));
+        let errors = [
+            ("1.5 press", "expected `SECONDS press|release KEY`"),
+            ("1.5 press KEY_A extra", "expected `SECONDS press|release KEY`"),
+            ("1.5 tap KEY_A", "unknown action `tap`"),
+            ("soon press KEY_A", "bad timestamp"),
+            ("1.5 press KEY_NOPE", "unknown key `KEY_NOPE`"),
+        ];
+        for (line, message) in errors {
+            assert_eq!(parse_event(line).unwrap_err().to_string(), message, "{}", line);
+        }
+
+        let path = temp_file("malformed");
+        fs::write(&path, "# zipchord trace v1\n\n1.0 press KEY_A\n1.1 tap KEY_A\n").unwrap();
+        let error = format!("{:#}", read(&path).unwrap_err());
+        assert!(error.contains(&format!("{}:4: invalid trace event `1.1 tap KEY_A`", path.display())), "{}", error);
+        assert!(error.ends_with("unknown action `tap`"), "{}", error);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn redaction_hides_character_keys() {
+        let mut out = Vec::new();
+        let mut writer = TraceWriter::redacted(&mut out, Layout::default()).unwrap();
+        let events = [
+            event(KeyCode::KEY_T, true, 1_000_000),
+            event(KeyCode::KEY_H, true, 1_010_000),
+            event(KeyCode::KEY_T, false, 1_020_000),
+            event(KeyCode::KEY_E, true, 1_030_000),
+            event(KeyCode::KEY_H, false, 1_040_000),
+            event(KeyCode::KEY_E, false, 1_050_000),
+            event(KeyCode::KEY_SPACE, true, 1_060_000),
+            event(KeyCode::KEY_SPACE, false, 1_070_000),
+            event(KeyCode::KEY_LEFTSHIFT, true, 1_080_000),
+        ];
+        for event in &events {
+            writer.write(event).unwrap();
+        }
+        drop(writer);
+
+        let content = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines, [
+            "# zipchord trace v1",
+            "# character keys are redacted",
+            "1.000000 press KEY_F13",
+            "1.010000 press KEY_F14",
+            "1.020000 release KEY_F13",
+            // The first free placeholder is reused
+            "1.030000 press KEY_F13",
+            "1.040000 release KEY_F14",
+            "1.050000 release KEY_F13",
+            "1.060000 press KEY_SPACE",
+            "1.070000 release KEY_SPACE",
+            "1.080000 press KEY_LEFTSHIFT",
+        ]);
+
+        // More keys held than placeholders share the last one
+        let mut out = Vec::new();
+        let mut writer = TraceWriter::redacted(&mut out, Layout::default()).unwrap();
+        for (i, c) in "qwertyuiopasd".chars().enumerate() {
+            let key = Layout::default().keystroke(c).unwrap().key;
+            writer.write(&event(key, true, i as u64)).unwrap();
+        }
+        drop(writer);
+        let content = String::from_utf8(out).unwrap();
+        assert!(content.ends_with("0.000011 press KEY_F24\n0.000012 press KEY_F24\n"), "{}", content);
+    }
+}
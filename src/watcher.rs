@@ -0,0 +1,114 @@
+use anyhow::{Context, Result};
+use log::{error, info};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+use zipchord::ChordLibrary;
+
+use crate::config::LIBRARY_EXTENSION;
+
+/// Watch a library directory and send a freshly loaded library after every
+/// change to a `.zc` file in it.
+///
+/// When `load` fails the change is skipped and the current library stays
+/// active. Included files outside `dir` are not watched.
+pub fn watch_libraries<F>(dir: &Path, mut load: F) -> Result<Receiver<ChordLibrary>>
+where
+    F: FnMut() -> Result<ChordLibrary> + Send + 'static,
+{
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC).context("Failed to initialize inotify")?;
+    // Completed writes and renames only, so half-written files aren't loaded
+    inotify
+        .add_watch(
+            dir,
+            AddWatchFlags::IN_CLOSE_WRITE
+                | AddWatchFlags::IN_MOVED_TO
+                | AddWatchFlags::IN_MOVED_FROM
+                | AddWatchFlags::IN_DELETE,
+        )
+        .with_context(|| format!("Failed to watch {}", dir.display()))?;
+
+    let (sender, receiver) = mpsc::channel();
+    let dir = dir.to_path_buf();
+
+    thread::Builder::new()
+        .name("library-watcher".to_string())
+        .spawn(move || loop {
+            let events = match inotify.read_events() {
+                Ok(events) => events,
+                Err(e) => {
+                    error!("Stopped watching {}: {}", dir.display(), e);
+                    return;
+                }
+            };
+
+            let changed: Vec<PathBuf> = events
+                .into_iter()
+                .filter_map(|event| event.name)
+                .map(|name| dir.join(name))
+                .filter(|path| path.extension().is_some_and(|ext| ext == LIBRARY_EXTENSION))
+                .collect();
+            if changed.is_empty() {
+                continue;
+            }
+
+            info!("Library change detected: {:?}", changed);
+            match load() {
+                Ok(library) => {
+                    if sender.send(library).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => error!("Keeping the current library: {:#}", e),
+            }
+        })
+        .context("Failed to start library watcher")?;
+
+    Ok(receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    // Long enough for the watcher thread to see an event
+    const SETTLE: Duration = Duration::from_millis(300);
+
+    #[test]
+    fn reloads_once_per_library_change() {
+        let dir = std::env::temp_dir().join(format!("zipchord-watch-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let loads = Arc::new(AtomicUsize::new(0));
+        let counted = loads.clone();
+        let reloads = watch_libraries(&dir, move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+            Ok(ChordLibrary::new())
+        }).unwrap();
+
+        // Cache writes, written then renamed as `cache::write` does, are ignored
+        fs::write(dir.join("english.zcc.partial"), "cache").unwrap();
+        fs::rename(dir.join("english.zcc.partial"), dir.join("english.zcc")).unwrap();
+        assert!(reloads.recv_timeout(SETTLE).is_err());
+
+        fs::write(dir.join("english.zc"), "ZIPCHORD LIBRARY v1\n").unwrap();
+        assert!(reloads.recv_timeout(SETTLE).is_ok());
+        assert!(reloads.recv_timeout(SETTLE).is_err());
+
+        // Editors saving through a temporary file
+        fs::write(dir.join("english.zc.tmp"), "ZIPCHORD LIBRARY v1\n").unwrap();
+        fs::rename(dir.join("english.zc.tmp"), dir.join("english.zc")).unwrap();
+        assert!(reloads.recv_timeout(SETTLE).is_ok());
+        assert!(reloads.recv_timeout(SETTLE).is_err());
+
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+        drop(reloads);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}